@@ -1,52 +1,232 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
 use bytes::Bytes;
 use serde::Serialize;
-use warp::{hyper::{HeaderMap, Method}, path::FullPath};
+use warp::{http::Version, hyper::{HeaderMap, Method}, path::FullPath};
 use askama::Template;
 
+use crate::{body::EchoBody, cookies, fingerprint::Http2Fingerprint, forwarded, tls::TlsInfo};
+
+/// Everything the echo handlers know about an incoming request.
+#[derive(Debug)]
+pub struct IncomingRequest {
+    pub method: Method,
+    pub path: FullPath,
+    pub query: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub version: Option<Version>,
+    pub remote_addr: Option<SocketAddr>,
+    pub tls: Option<TlsInfo>,
+    pub http2_fingerprint: Option<Http2Fingerprint>,
+    pub stream_id: Option<u32>
+}
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 pub struct EchoResponse {
+    #[serde(skip_serializing_if="Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    client_ip: Option<String>,
     method: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    version: Option<String>,
+    // The protocol as ALPN names it, telling h2 over TLS from cleartext h2c.
+    #[serde(skip_serializing_if="Option::is_none")]
+    protocol: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    stream_id: Option<u32>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    tls: Option<TlsInfo>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    http2_fingerprint: Option<Http2Fingerprint>,
     headers: Vec<(String, String)>,
     path: String,
     #[serde(skip_serializing_if="Option::is_none")]
-    body: Option<String>,
+    query_string: Option<String>,
+    #[serde(skip_serializing_if="BTreeMap::is_empty")]
+    query: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if="BTreeMap::is_empty")]
+    cookies: BTreeMap<String, String>,
+    #[serde(flatten)]
+    body: Option<EchoBody>,
     server: String
 }
 
 impl EchoResponse {
-    pub fn new(method: Method, headers: HeaderMap, path: FullPath, bytes: Bytes, server: String) -> Self {
-        let method = method.to_string();
-        let headers = headers.iter()
+    pub fn new(request: &IncomingRequest, server: String) -> Self {
+        let source = request.remote_addr.map(|addr| addr.to_string());
+        let client_ip = request.remote_addr
+            .map(|addr| forwarded::client_ip(addr.ip(), &request.headers).to_string());
+        let method = request.method.to_string();
+        let version = request.version.map(|v| format!("{:?}", v));
+        let protocol = request.version.and_then(|version| match version {
+            Version::HTTP_2 if request.tls.is_some() => Some("h2"),
+            Version::HTTP_2 => Some("h2c"),
+            Version::HTTP_11 => Some("http/1.1"),
+            Version::HTTP_10 => Some("http/1.0"),
+            Version::HTTP_09 => Some("http/0.9"),
+            _ => None
+        }).map(str::to_string);
+        let headers = request.headers.iter()
             .map( |(k,v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).to_string()) ).collect();
-        let path = path.as_str().to_string();
-        let body = Some(String::from_utf8_lossy(&bytes).to_string()).filter(|s| !s.is_empty() );
+        let path = request.path.as_str().to_string();
+        let query_string = Some(request.query.clone()).filter(|s| !s.is_empty() );
+        let mut query: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let pairs: Vec<(String, String)> = serde_urlencoded::from_str(&request.query).unwrap_or_default();
+        for (key, value) in pairs {
+            query.entry(key).or_default().push(value);
+        }
+        let body = EchoBody::new(&request.body, &request.headers);
         EchoResponse {
+            source,
+            client_ip,
             method,
+            version,
+            protocol,
+            stream_id: request.stream_id,
+            tls: request.tls.clone(),
+            http2_fingerprint: request.http2_fingerprint.clone(),
             headers,
             path,
+            query_string,
+            query,
+            cookies: cookies::parse(&request.headers),
             body,
             server
         }
     }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn query(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.query
+    }
+
+    /// The body as echoed, base64 encoded if it isn't text.
+    pub fn body_text(&self) -> Option<&str> {
+        self.body.as_ref().map(|body| body.body())
+    }
+
+    /// A plain text dump of the request, in the style of `curl -v`.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if let Some(source) = &self.source {
+            text.push_str(&format!("* Connected from {}", source));
+            if let Some(client_ip) = &self.client_ip {
+                text.push_str(&format!(" (client {})", client_ip));
+            }
+            text.push('\n');
+        }
+        if let Some(tls) = &self.tls {
+            text.push_str(&format!("* TLS connection using {}\n", tls.summary()));
+            if let Some(certificate) = &tls.client_certificate {
+                text.push_str(&format!("* Client certificate: {} (issuer {})\n", certificate.subject, certificate.issuer));
+            }
+            if let Some(fingerprint) = &tls.fingerprint {
+                text.push_str(&format!("* JA3 {}, JA4 {}\n", fingerprint.ja3_hash, fingerprint.ja4));
+            }
+        }
+        if let Some(fingerprint) = &self.http2_fingerprint {
+            text.push_str(&format!("* HTTP/2 fingerprint {}\n", fingerprint.akamai));
+        }
+        text.push_str(&format!("* Served by {}\n", self.server));
+        let target = match &self.query_string {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone()
+        };
+        let version = self.version.as_deref().unwrap_or("HTTP/1.1");
+        text.push_str(&format!("> {} {} {}\n", self.method, target, version));
+        for (name, value) in &self.headers {
+            text.push_str(&format!("> {}: {}\n", name, value));
+        }
+        text.push_str(">\n");
+        if let Some(body) = &self.body {
+            if let Some(encoding) = body.encoding() {
+                text.push_str(&format!("[{} bytes, {} encoded]\n", body.len(), encoding));
+            }
+            text.push_str(body.body());
+            text.push('\n');
+        }
+        text
+    }
 }
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct IndexTemplate {
+    details: Vec<(String, String)>,
     headers: Vec<(String, String)>,
     server: String
 }
 
 impl IndexTemplate {
-    pub fn new(headers: HeaderMap, server: String) -> Self {
+    pub fn new(headers: HeaderMap, server: String, tls: Option<&TlsInfo>) -> Self {
         let headers = headers.iter()
             .map( |(k,v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).to_string()) ).collect();
         IndexTemplate {
+            details: tls.map(tls_details).unwrap_or_default(),
             headers,
             server
         }
     }
+
+    /// The HTML view of an echo, listing the request details above the headers.
+    pub fn from_echo(echo: &EchoResponse) -> Self {
+        let mut details = vec![
+            ("Method".to_string(), echo.method.clone()),
+            ("Path".to_string(), echo.path.clone())
+        ];
+        let optional = [
+            ("Query", echo.query_string.clone()),
+            ("Version", echo.version.clone()),
+            ("Protocol", echo.protocol.clone()),
+            ("Stream", echo.stream_id.map(|id| id.to_string())),
+            ("Source", echo.source.clone()),
+            ("Client IP", echo.client_ip.clone()),
+            ("Body", echo.body.as_ref().map(|b| b.body().to_string()))
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                details.push((name.to_string(), value));
+            }
+        }
+        if let Some(tls) = &echo.tls {
+            details.extend(tls_details(tls));
+        }
+        if let Some(fingerprint) = &echo.http2_fingerprint {
+            details.push(("HTTP/2 Fingerprint".to_string(), fingerprint.akamai.clone()));
+        }
+        IndexTemplate {
+            details,
+            headers: echo.headers.clone(),
+            server: echo.server.clone()
+        }
+    }
+}
+
+// The TLS connection, client certificate and fingerprints as rows of the HTML page.
+fn tls_details(tls: &TlsInfo) -> Vec<(String, String)> {
+    let mut details = vec![("TLS".to_string(), tls.summary())];
+    if let Some(certificate) = &tls.client_certificate {
+        details.extend(certificate.details());
+    }
+    if let Some(fingerprint) = &tls.fingerprint {
+        details.push(("JA3".to_string(), format!("{} ({})", fingerprint.ja3_hash, fingerprint.ja3)));
+        details.push(("JA4".to_string(), fingerprint.ja4.clone()));
+    }
+    details
 }
 
 #[derive(Template)]
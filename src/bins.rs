@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use warp::{Filter, Reply, filters::BoxedFilter, hyper::StatusCode, reply::Response};
+
+use crate::{api::IncomingRequest, echo, history::{self, History, HistoryQuery}};
+
+const DEFAULT_BIN_SIZE: usize = 100;
+const DEFAULT_MAX_BINS: usize = 1000;
+const DEFAULT_BIN_TTL_SECS: i64 = 24 * 60 * 60;
+
+lazy_static! {
+    static ref BINS: RwLock<HashMap<String, Bin>> = RwLock::new(HashMap::new());
+
+    /// The number of requests kept per bin, set with the `BIN_SIZE` environment variable.
+    static ref BIN_SIZE: usize = std::env::var("BIN_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(DEFAULT_BIN_SIZE);
+
+    /// How many bins may exist at once, set with the `MAX_BINS` environment variable.
+    static ref MAX_BINS: usize = std::env::var("MAX_BINS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BINS);
+
+    /// How long a bin lives, in seconds, set with the `BIN_TTL` environment variable.
+    static ref BIN_TTL: TimeDelta = {
+        let seconds = std::env::var("BIN_TTL")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .unwrap_or(DEFAULT_BIN_TTL_SECS);
+        match TimeDelta::try_seconds(seconds) {
+            Some(ttl) => ttl,
+            None => panic!("BIN_TTL: {} seconds is out of range", seconds)
+        }
+    };
+}
+
+/// Reads the bin settings at startup so a broken value is reported straight away.
+pub fn init() {
+    lazy_static::initialize(&BIN_TTL);
+}
+
+/// A capture bucket. Requests sent to `/bins/{id}/...` are only recorded in their bin.
+#[derive(Clone)]
+struct Bin {
+    created: DateTime<Utc>,
+    history: Arc<RwLock<History>>
+}
+
+impl Bin {
+    fn expires(&self) -> DateTime<Utc> {
+        // A TTL that runs past the end of time never expires.
+        self.created.checked_add_signed(*BIN_TTL).unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires() <= Utc::now()
+    }
+}
+
+#[derive(Serialize)]
+struct BinSummary {
+    id: String,
+    url: String,
+    created: DateTime<Utc>,
+    expires: DateTime<Utc>,
+    requests: usize
+}
+
+impl BinSummary {
+    fn new(id: &str, bin: &Bin) -> Self {
+        BinSummary {
+            id: id.to_string(),
+            url: format!("/bins/{}", id),
+            created: bin.created,
+            expires: bin.expires(),
+            requests: bin.history.read().unwrap().len()
+        }
+    }
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status
+    ).into_response()
+}
+
+fn bin_not_found(id: &str) -> Response {
+    error(StatusCode::NOT_FOUND, format!("No bin with id {}", id))
+}
+
+fn find_bin(id: &str) -> Option<Bin> {
+    BINS.read().unwrap().get(id).filter(|bin| !bin.is_expired()).cloned()
+}
+
+/// The requests captured by the bin with the given id.
+pub fn history(id: &str) -> Option<Arc<RwLock<History>>> {
+    find_bin(id).map(|bin| bin.history)
+}
+
+async fn create_bin() -> Result<impl Reply, Infallible> {
+    let id = Uuid::new_v4().simple().to_string();
+    let bin = Bin {
+        created: Utc::now(),
+        history: Arc::new(RwLock::new(History::new(*BIN_SIZE)))
+    };
+    let summary = BinSummary::new(&id, &bin);
+    let mut bins = BINS.write().unwrap();
+    bins.retain(|_, bin| !bin.is_expired());
+    if bins.len() >= *MAX_BINS {
+        return Ok(error(StatusCode::INSUFFICIENT_STORAGE, format!("All {} bins are in use", *MAX_BINS)));
+    }
+    bins.insert(id, bin);
+    Ok(warp::reply::with_status(warp::reply::json(&summary), StatusCode::CREATED).into_response())
+}
+
+async fn list_bins() -> Result<impl Reply, Infallible> {
+    let mut bins = BINS.write().unwrap();
+    bins.retain(|_, bin| !bin.is_expired());
+    let mut summaries: Vec<BinSummary> = bins.iter()
+        .map(|(id, bin)| BinSummary::new(id, bin))
+        .collect();
+    summaries.sort_by_key(|summary| summary.created);
+    Ok(warp::reply::json(&summaries))
+}
+
+async fn list_requests(id: String, query: HistoryQuery) -> Result<impl Reply, Infallible> {
+    let reply = match find_bin(&id) {
+        Some(bin) => warp::reply::json(&bin.history.read().unwrap().list(&query)).into_response(),
+        None => bin_not_found(&id)
+    };
+    Ok(reply)
+}
+
+async fn delete_bin(id: String) -> Result<impl Reply, Infallible> {
+    let reply = match BINS.write().unwrap().remove(&id) {
+        Some(_) => StatusCode::NO_CONTENT.into_response(),
+        None => bin_not_found(&id)
+    };
+    Ok(reply)
+}
+
+async fn capture(id: String, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let reply = match find_bin(&id) {
+        Some(bin) => echo::controlled(request, StatusCode::OK, &bin.history).await,
+        None => bin_not_found(&id)
+    };
+    Ok(reply)
+}
+
+pub fn bins_handler() -> BoxedFilter<(impl Reply,)> {
+    let create = warp::path::end()
+        .and(warp::post())
+        .and_then(create_bin);
+
+    let requests = warp::path::param::<String>()
+        .and(warp::path("requests"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and_then(list_requests)
+        .recover(history::handle_invalid_query);
+
+    let delete = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and_then(delete_bin);
+
+    let capture = warp::path::param::<String>()
+        .and(echo::incoming_request())
+        .and_then(capture);
+
+    create.or(requests).or(delete).or(capture).boxed()
+}
+
+/// Lists every bin, for operators only as the ids are what keeps bins apart.
+pub fn admin_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(warp::get())
+        .and_then(list_bins)
+        .boxed()
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use encoding_rs::Encoding;
+use md5::Md5;
+use mime::Mime;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use warp::hyper::HeaderMap;
+
+use crate::compression;
+
+/// A request body as reported in the echo. Text bodies are returned as is,
+/// anything that can't be decoded is returned base64 encoded so the bytes
+/// survive the round trip.
+#[derive(Clone, Serialize)]
+pub struct EchoBody {
+    body: String,
+    #[serde(skip_serializing_if="Option::is_none")]
+    body_encoding: Option<&'static str>,
+    // Always over the bytes as they were received, so uploads can be verified.
+    body_length: usize,
+    body_sha256: String,
+    body_md5: String,
+    // The `Content-Encoding` the body arrived with, and the body after decoding.
+    #[serde(skip_serializing_if="Option::is_none")]
+    body_content_encoding: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    body_decoded_length: Option<usize>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    body_decoded_sha256: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    body_decoded_md5: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    json: Option<serde_json::Value>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    form: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    multipart: Option<Vec<Part>>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    body_decode_error: Option<String>
+}
+
+/// A single part of a `multipart/form-data` body.
+#[derive(Clone, Serialize)]
+pub struct Part {
+    #[serde(skip_serializing_if="Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    filename: Option<String>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    content_type: Option<String>,
+    size: usize,
+    sha256: String,
+    // Only given for plain form fields, files are summarised by size and digest.
+    #[serde(skip_serializing_if="Option::is_none")]
+    value: Option<String>
+}
+
+impl EchoBody {
+    /// Describes the body, or returns `None` when there is no body at all.
+    /// Bodies sent with a `Content-Encoding` are decompressed first.
+    pub fn new(raw: &[u8], headers: &HeaderMap) -> Option<Self> {
+        if raw.is_empty() {
+            return None;
+        }
+        let (decoded, decompress_error) = match compression::decode(raw, headers) {
+            Ok(decoded) => (decoded, None),
+            Err(error) => (None, Some(error))
+        };
+        let bytes = decoded.as_ref().map(|(_, bytes)| bytes.as_slice()).unwrap_or(raw);
+        let (body, body_encoding) = match decode_text(bytes, headers) {
+            Some(text) => (text, None),
+            None => (STANDARD.encode(bytes), Some("base64"))
+        };
+        let mut echo = EchoBody {
+            body,
+            body_encoding,
+            body_length: raw.len(),
+            body_sha256: format!("{:x}", Sha256::digest(raw)),
+            body_md5: format!("{:x}", Md5::digest(raw)),
+            body_content_encoding: decoded.as_ref().map(|(coding, _)| coding.clone()),
+            body_decoded_length: decoded.as_ref().map(|(_, bytes)| bytes.len()),
+            body_decoded_sha256: decoded.as_ref().map(|(_, bytes)| format!("{:x}", Sha256::digest(bytes))),
+            body_decoded_md5: decoded.as_ref().map(|(_, bytes)| format!("{:x}", Md5::digest(bytes))),
+            json: None,
+            form: None,
+            multipart: None,
+            body_decode_error: decompress_error
+        };
+        // There is no point parsing a body that is still compressed.
+        if echo.body_decode_error.is_none() {
+            if let Err(error) = echo.decode(bytes, headers) {
+                echo.body_decode_error = Some(error);
+            }
+        }
+        Some(echo)
+    }
+
+    /// The body as returned in the echo, either text or base64.
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn encoding(&self) -> Option<&'static str> {
+        self.body_encoding
+    }
+
+    /// The length of the body as returned in the echo, after any decompression.
+    pub fn len(&self) -> usize {
+        self.body_decoded_length.unwrap_or(self.body_length)
+    }
+
+    // Decodes JSON, urlencoded form and multipart bodies into their structure.
+    fn decode(&mut self, bytes: &[u8], headers: &HeaderMap) -> Result<(), String> {
+        let Some(content_type) = content_type(headers) else {
+            return Ok(());
+        };
+        match (content_type.type_(), content_type.subtype(), content_type.suffix()) {
+            (mime::APPLICATION, mime::JSON, _) | (mime::APPLICATION, _, Some(mime::JSON)) => {
+                let json = serde_json::from_slice(bytes).map_err(|e| format!("invalid JSON: {}", e))?;
+                self.json = Some(json);
+            },
+            (mime::APPLICATION, mime::WWW_FORM_URLENCODED, _) => {
+                let pairs: Vec<(String, String)> = serde_urlencoded::from_bytes(bytes)
+                    .map_err(|e| format!("invalid form: {}", e))?;
+                let mut form: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                for (key, value) in pairs {
+                    form.entry(key).or_default().push(value);
+                }
+                self.form = Some(form);
+            },
+            (mime::MULTIPART, mime::FORM_DATA, _) => {
+                let boundary = content_type.get_param(mime::BOUNDARY)
+                    .ok_or_else(|| "multipart body without a boundary".to_string())?;
+                self.multipart = Some(parse_multipart(bytes, boundary.as_str())?);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn content_type(headers: &HeaderMap) -> Option<Mime> {
+    headers.get("content-type")?.to_str().ok()?.parse().ok()
+}
+
+fn parse_multipart(bytes: &[u8], boundary: &str) -> Result<Vec<Part>, String> {
+    let delimiter = format!("\r\n--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+    // The first delimiter doesn't need to be preceded by a line break.
+    let start = find(bytes, &delimiter[2..]).ok_or("multipart boundary not found")?;
+    let mut rest = &bytes[start + delimiter.len() - 2..];
+
+    let mut parts = Vec::new();
+    while !rest.starts_with(b"--") {
+        // Skip any transport padding up to the end of the delimiter line.
+        let line_end = find(rest, b"\r\n").ok_or("unterminated multipart delimiter")?;
+        rest = &rest[line_end + 2..];
+        let end = find(rest, delimiter).ok_or("unterminated multipart part")?;
+        parts.push(parse_part(&rest[..end])?);
+        rest = &rest[end + delimiter.len()..];
+    }
+    Ok(parts)
+}
+
+fn parse_part(part: &[u8]) -> Result<Part, String> {
+    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let (offset, headers) = match httparse::parse_headers(part, &mut headers) {
+        Ok(httparse::Status::Complete(parsed)) => parsed,
+        _ => return Err("invalid multipart part headers".to_string())
+    };
+    let header = |name: &str| headers.iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| String::from_utf8_lossy(h.value).to_string());
+
+    let disposition = header("content-disposition").map(|d| parameters(&d)).unwrap_or_default();
+    let name = disposition.get("name").cloned();
+    let filename = disposition.get("filename").cloned();
+    let content = &part[offset..];
+    let value = match filename {
+        None => std::str::from_utf8(content).ok().map(str::to_string),
+        Some(_) => None
+    };
+    Ok(Part {
+        name,
+        filename,
+        content_type: header("content-type"),
+        size: content.len(),
+        sha256: format!("{:x}", Sha256::digest(content)),
+        value
+    })
+}
+
+// Parses the `name=value` parameters of a header such as Content-Disposition,
+// allowing for quoted values that contain separators.
+fn parameters(header: &str) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    let mut rest = header.split_once(';').map(|(_, p)| p).unwrap_or("");
+    while let Some((name, value)) = rest.split_once('=') {
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim_start();
+        let (value, remainder) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let remainder = quoted[end..].trim_start_matches('"');
+                (quoted[..end].to_string(), remainder.split_once(';').map(|(_, r)| r).unwrap_or(""))
+            },
+            None => match value.split_once(';') {
+                Some((value, remainder)) => (value.trim().to_string(), remainder),
+                None => (value.trim().to_string(), "")
+            }
+        };
+        params.insert(name, value);
+        rest = remainder;
+    }
+    params
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+// Decodes the body using the charset from the Content-Type, falling back to
+// UTF-8 when there is none or it isn't recognised.
+fn decode_text(bytes: &[u8], headers: &HeaderMap) -> Option<String> {
+    let encoding = charset(headers)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(encoding_rs::UTF_8);
+    encoding.decode_without_bom_handling_and_without_replacement(bytes)
+        .map(|text| text.into_owned())
+}
+
+fn charset(headers: &HeaderMap) -> Option<String> {
+    content_type(headers)?.get_param(mime::CHARSET).map(|charset| charset.to_string())
+}
@@ -0,0 +1,557 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use rand::Rng;
+use rand_distr::{Distribution as _, LogNormal, Normal};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+use warp::{
+    Filter, Rejection, Reply, filters::BoxedFilter, http::HeaderValue, hyper::{Method, StatusCode},
+    path::FullPath, reply::Response
+};
+
+use crate::{config, matcher::Pattern, metrics, server::{self, BodyStream}};
+
+// Upper bound for injected latency, in line with the `echo_delay` limit.
+const MAX_LATENCY: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// Rules and windows loaded from the file named by the `CHAOS_CONFIG` environment variable.
+    static ref CHAOS: ChaosConfig = config::load_from_env::<ChaosConfig>("CHAOS_CONFIG")
+        .unwrap_or_default();
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChaosConfig {
+    #[serde(default)]
+    rules: Vec<ChaosRule>,
+    #[serde(default)]
+    windows: Vec<ChaosWindow>
+}
+
+/// The faults to inject into the requests matching the rule. Each fault is
+/// rolled for independently.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChaosRule {
+    name: Option<String>,
+    path_pattern: Option<Pattern>,
+    #[serde(default)]
+    methods: Vec<String>,
+    error: Option<ErrorFault>,
+    latency: Option<LatencyFault>,
+    abort: Option<CutFault>,
+    truncate: Option<CutFault>
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+fn default_error_status() -> u16 {
+    500
+}
+
+fn half() -> f64 {
+    0.5
+}
+
+/// Answers with an error status instead of handling the request.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ErrorFault {
+    #[serde(default = "always")]
+    probability: f64,
+    #[serde(default = "default_error_status")]
+    status: u16
+}
+
+/// Delays the request by a duration drawn from the distribution.
+#[derive(Deserialize)]
+struct LatencyFault {
+    #[serde(default = "always")]
+    probability: f64,
+    #[serde(flatten)]
+    distribution: Distribution
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "distribution", rename_all = "snake_case", deny_unknown_fields)]
+enum Distribution {
+    Fixed { ms: f64 },
+    Uniform { min_ms: f64, max_ms: f64 },
+    Normal { mean_ms: f64, stddev_ms: f64 },
+    LogNormal { median_ms: f64, sigma: f64 }
+}
+
+/// Cuts the response body short, after `after_bytes` or else after the
+/// given fraction of the body.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CutFault {
+    #[serde(default = "always")]
+    probability: f64,
+    after_bytes: Option<usize>,
+    #[serde(default = "half")]
+    fraction: f64
+}
+
+impl ChaosRule {
+    fn label(&self) -> String {
+        self.name.clone()
+            .or_else(|| self.path_pattern.as_ref().map(Pattern::to_string))
+            .unwrap_or_else(|| "*".to_string())
+    }
+
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        matches_request(self.path_pattern.as_ref(), &self.methods, method, path)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let probabilities = [
+            self.abort.as_ref().map(|f| f.probability),
+            self.truncate.as_ref().map(|f| f.probability)
+        ];
+        check_probabilities(&probabilities)?;
+        check_faults(self.error.as_ref(), self.latency.as_ref())?;
+        let fractions = [self.abort.as_ref(), self.truncate.as_ref()];
+        if fractions.into_iter().flatten().any(|cut| !(0.0..=1.0).contains(&cut.fraction)) {
+            return Err("fractions must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn matches_request(path_pattern: Option<&Pattern>, methods: &[String], method: &Method, path: &str) -> bool {
+    path_pattern.is_none_or(|pattern| pattern.is_match(path))
+        && (methods.is_empty() || methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str())))
+}
+
+fn check_probabilities(probabilities: &[Option<f64>]) -> Result<(), String> {
+    if probabilities.iter().flatten().any(|p| !(0.0..=1.0).contains(p)) {
+        return Err("probabilities must be between 0 and 1".to_string());
+    }
+    Ok(())
+}
+
+fn check_faults(error: Option<&ErrorFault>, latency: Option<&LatencyFault>) -> Result<(), String> {
+    check_probabilities(&[error.map(|f| f.probability), latency.map(|f| f.probability)])?;
+    if let Some(error) = error {
+        StatusCode::from_u16(error.status).map_err(|_| format!("invalid status {}", error.status))?;
+    }
+    if let Some(latency) = latency {
+        latency.distribution.check()?;
+    }
+    Ok(())
+}
+
+/// A recurring period during which the matching requests misbehave. Windows
+/// start on a cron schedule, or at every multiple of an interval since the
+/// Unix epoch, and stay open for their duration.
+#[derive(Deserialize)]
+#[serde(try_from = "WindowConfig")]
+struct ChaosWindow {
+    name: String,
+    path_pattern: Option<Pattern>,
+    methods: Vec<String>,
+    schedule: Schedule,
+    duration: chrono::Duration,
+    error: Option<ErrorFault>,
+    latency: Option<LatencyFault>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WindowConfig {
+    name: String,
+    path_pattern: Option<Pattern>,
+    #[serde(default)]
+    methods: Vec<String>,
+    cron: Option<String>,
+    every: Option<String>,
+    duration: String,
+    error: Option<ErrorFault>,
+    latency: Option<LatencyFault>
+}
+
+enum Schedule {
+    Cron(Box<cron::Schedule>),
+    Every(Duration)
+}
+
+impl TryFrom<WindowConfig> for ChaosWindow {
+    type Error = String;
+
+    fn try_from(config: WindowConfig) -> Result<Self, Self::Error> {
+        let schedule = match (config.cron, config.every) {
+            (Some(cron), None) => {
+                // Five fields are read as a standard crontab line, which has no seconds.
+                let expression = if cron.split_whitespace().count() == 5 { format!("0 {}", cron) } else { cron };
+                let schedule = expression.parse::<cron::Schedule>()
+                    .map_err(|e| format!("invalid cron expression {:?}: {}", expression, e))?;
+                Schedule::Cron(Box::new(schedule))
+            },
+            (None, Some(every)) => {
+                let interval = parse_duration(&every)
+                    .filter(|interval| !interval.is_zero())
+                    .ok_or_else(|| format!("invalid interval {:?}", every))?;
+                Schedule::Every(interval)
+            },
+            _ => return Err(format!("window {} needs either a cron expression or an every interval", config.name))
+        };
+        let duration = parse_duration(&config.duration)
+            .ok_or_else(|| format!("invalid duration {:?}", config.duration))?;
+        let duration = chrono::Duration::from_std(duration)
+            .map_err(|_| format!("duration {:?} is too long", config.duration))?;
+        Ok(ChaosWindow {
+            name: config.name,
+            path_pattern: config.path_pattern,
+            methods: config.methods,
+            schedule,
+            duration,
+            error: config.error,
+            latency: config.latency
+        })
+    }
+}
+
+/// Where a window stands at a given moment.
+#[derive(Serialize)]
+struct WindowState {
+    name: String,
+    schedule: String,
+    duration_secs: f64,
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_until: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_start: Option<DateTime<Utc>>
+}
+
+impl ChaosWindow {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        matches_request(self.path_pattern.as_ref(), &self.methods, method, path)
+    }
+
+    fn state(&self, now: DateTime<Utc>) -> WindowState {
+        let duration = self.duration;
+        // A window long enough to run past the ends of time is open for good once started.
+        let end = |start: DateTime<Utc>| start.checked_add_signed(duration).unwrap_or(DateTime::<Utc>::MAX_UTC);
+        let (active_until, next_start, schedule) = match &self.schedule {
+            Schedule::Cron(schedule) => {
+                // The window that started last is the only one that can still be open.
+                let active_until = schedule.after(&now).next_back().map(end).filter(|until| *until > now);
+                let next = schedule.after(&now).next();
+                (active_until, next, schedule.source().to_string())
+            },
+            Schedule::Every(interval) => {
+                let interval = chrono::Duration::from_std(*interval).unwrap_or(chrono::Duration::MAX);
+                let elapsed = now.timestamp_millis().rem_euclid(interval.num_milliseconds().max(1));
+                let start = DateTime::from_timestamp_millis(now.timestamp_millis() - elapsed).unwrap_or(now);
+                let active = elapsed < duration.num_milliseconds();
+                (active.then(|| end(start)), start.checked_add_signed(interval), format!("every {}s", interval.num_seconds()))
+            }
+        };
+        WindowState {
+            name: self.name.clone(),
+            schedule,
+            duration_secs: self.duration.num_milliseconds() as f64 / 1000.0,
+            active: active_until.is_some(),
+            active_until,
+            next_start
+        }
+    }
+}
+
+/// Parses durations such as `500ms`, `30s`, `10m` and `1h`. A plain number is in seconds.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let seconds = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+impl Distribution {
+    fn check(&self) -> Result<(), String> {
+        let valid = match *self {
+            Distribution::Fixed { ms } => ms >= 0.0,
+            Distribution::Uniform { min_ms, max_ms } => min_ms >= 0.0 && min_ms <= max_ms,
+            Distribution::Normal { mean_ms, stddev_ms } => stddev_ms >= 0.0 && Normal::new(mean_ms, stddev_ms).is_ok(),
+            Distribution::LogNormal { median_ms, sigma } => {
+                median_ms > 0.0 && sigma >= 0.0 && LogNormal::new(median_ms.ln(), sigma).is_ok()
+            }
+        };
+        if valid { Ok(()) } else { Err("invalid latency distribution".to_string()) }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Duration {
+        let ms = match *self {
+            Distribution::Fixed { ms } => ms,
+            Distribution::Uniform { min_ms, max_ms } if min_ms < max_ms => rng.random_range(min_ms..max_ms),
+            Distribution::Uniform { min_ms, .. } => min_ms,
+            Distribution::Normal { mean_ms, stddev_ms } => Normal::new(mean_ms, stddev_ms)
+                .map(|normal| normal.sample(rng))
+                .unwrap_or(mean_ms),
+            Distribution::LogNormal { median_ms, sigma } => LogNormal::new(median_ms.ln(), sigma)
+                .map(|log_normal| log_normal.sample(rng))
+                .unwrap_or(median_ms)
+        };
+        // Clamped first, as a sample from a long tail can be too large for a Duration.
+        Duration::from_secs_f64((ms / 1000.0).max(0.0).min(MAX_LATENCY.as_secs_f64()))
+    }
+}
+
+impl CutFault {
+    // How many bytes of the body are sent, given the length of the body when known.
+    fn limit(&self, length: Option<u64>) -> usize {
+        self.after_bytes
+            .or_else(|| length.map(|length| (length as f64 * self.fraction) as usize))
+            .unwrap_or(0)
+    }
+}
+
+/// Marks a response whose body chaos cuts short, in its extensions. Such a
+/// response has to reach the client as it is, or the cut would be hidden.
+#[derive(Clone, Copy, Debug)]
+pub struct Cut;
+
+/// The faults rolled for a request.
+#[derive(Default)]
+struct Plan {
+    rule: String,
+    abort: Option<&'static CutFault>,
+    truncate: Option<&'static CutFault>
+}
+
+impl Plan {
+    fn apply(self, mut response: Response) -> Response {
+        let length = server::body_length(&response);
+        if let Some(abort) = self.abort {
+            let limit = abort.limit(length);
+            // Declaring the full length makes the client wait for the bytes that never come.
+            if let Some(length) = length {
+                response.headers_mut().insert("content-length", HeaderValue::from(length));
+            }
+            count(&self.rule, "abort");
+            response.extensions_mut().insert(Cut);
+            return server::map_body(response, |body| cut(body, limit, true));
+        }
+        if let Some(truncate) = self.truncate {
+            let limit = truncate.limit(length);
+            response.headers_mut().remove("content-length");
+            count(&self.rule, "truncate");
+            response.extensions_mut().insert(Cut);
+            return server::map_body(response, |body| cut(body, limit, false));
+        }
+        response
+    }
+}
+
+/// Rejection carrying an injected error, turned into the response by `recover`.
+#[derive(Debug)]
+struct InjectedError {
+    // The rule or window that injected the error.
+    cause: String,
+    status: StatusCode
+}
+
+impl warp::reject::Reject for InjectedError {}
+
+fn count(rule: &str, fault: &str) {
+    metrics::CHAOS_FAULTS.with_label_values(&[rule, fault]).inc();
+}
+
+// Ends the body after `limit` bytes, failing the stream when aborting so the
+// connection is dropped instead of the response being completed.
+fn cut(body: BodyStream, limit: usize, abort: bool) -> BodyStream {
+    futures::stream::unfold((body, limit, false), move |(mut body, remaining, done)| async move {
+        if done {
+            return None;
+        }
+        if remaining == 0 {
+            if !abort {
+                return None;
+            }
+            // Give the server a moment to flush what was sent before the connection goes.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            return Some((Err("connection aborted by chaos rule".into()), (body, 0, true)));
+        }
+        match body.next().await {
+            Some(Ok(mut chunk)) => {
+                chunk.truncate(remaining);
+                let remaining = remaining - chunk.len();
+                Some((Ok(chunk), (body, remaining, false)))
+            },
+            other => other.map(|item| (item, (body, remaining, true)))
+        }
+    }).boxed()
+}
+
+async fn plan(method: Method, path: FullPath) -> Result<Plan, Rejection> {
+    // A catch-all rule shouldn't lock operators out of the admin endpoints.
+    if path.as_str() == "/_admin" || path.as_str().starts_with("/_admin/") {
+        return Ok(Plan::default());
+    }
+    let rule = CHAOS.rules.iter().find(|rule| rule.matches(&method, path.as_str()));
+    let window = CHAOS.windows.iter()
+        .find(|window| window.matches(&method, path.as_str()) && window.state(Utc::now()).active);
+    if rule.is_none() && window.is_none() {
+        return Ok(Plan::default());
+    }
+    let mut latency = Duration::ZERO;
+    let mut error = None;
+    let mut plan = Plan::default();
+    {
+        let mut rng = rand::rng();
+        let sources = rule.map(|rule| (rule.label(), "rule", rule.error.as_ref(), rule.latency.as_ref())).into_iter()
+            .chain(window.map(|window| (window.name.clone(), "window", window.error.as_ref(), window.latency.as_ref())));
+        for (label, kind, error_fault, latency_fault) in sources {
+            if let Some(fault) = latency_fault.filter(|f| rng.random_bool(f.probability)) {
+                count(&label, "latency");
+                latency += fault.distribution.sample(&mut rng);
+            }
+            if let Some(fault) = error_fault.filter(|f| error.is_none() && rng.random_bool(f.probability)) {
+                count(&label, "error");
+                error = Some(InjectedError {
+                    cause: format!("{} {}", kind, label),
+                    status: StatusCode::from_u16(fault.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+                });
+            }
+        }
+        if let Some(rule) = rule {
+            plan = Plan {
+                rule: rule.label(),
+                abort: rule.abort.as_ref().filter(|f| rng.random_bool(f.probability)),
+                truncate: rule.truncate.as_ref().filter(|f| rng.random_bool(f.probability))
+            };
+        }
+    }
+    if !latency.is_zero() {
+        debug!(path = path.as_str(), ?latency, "injecting latency");
+        tokio::time::sleep(latency.min(MAX_LATENCY)).await;
+    }
+    if let Some(error) = error {
+        return Err(warp::reject::custom(error));
+    }
+    Ok(plan)
+}
+
+async fn injected_error(err: Rejection) -> Result<Response, Rejection> {
+    let Some(injected) = err.find::<InjectedError>() else {
+        return Err(err);
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "error": format!("Fault injected by chaos {}", injected.cause)
+        })),
+        injected.status
+    ).into_response())
+}
+
+// Keeps the window gauges current and logs windows opening and closing.
+async fn watch_windows() {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut open = vec![false; CHAOS.windows.len()];
+    loop {
+        ticker.tick().await;
+        let now = Utc::now();
+        for (window, was_open) in CHAOS.windows.iter().zip(open.iter_mut()) {
+            let state = window.state(now);
+            metrics::CHAOS_WINDOW_ACTIVE.with_label_values(&[&window.name]).set(state.active as i64);
+            if let Some(next_start) = state.next_start {
+                metrics::CHAOS_WINDOW_NEXT_START.with_label_values(&[&window.name]).set(next_start.timestamp());
+            }
+            if state.active != *was_open {
+                match state.active_until {
+                    Some(until) => warn!(window = window.name, %until, "Chaos window opened"),
+                    None => info!(window = window.name, "Chaos window closed")
+                }
+                *was_open = state.active;
+            }
+        }
+    }
+}
+
+/// Loads the rules and windows at startup so a broken file is reported
+/// straight away, and starts tracking the windows.
+pub fn init() {
+    lazy_static::initialize(&CHAOS);
+    for rule in CHAOS.rules.iter() {
+        if let Err(error) = rule.check() {
+            panic!("CHAOS_CONFIG: rule {}: {}", rule.label(), error);
+        }
+    }
+    for window in CHAOS.windows.iter() {
+        if let Err(error) = check_faults(window.error.as_ref(), window.latency.as_ref()) {
+            panic!("CHAOS_CONFIG: window {}: {}", window.name, error);
+        }
+    }
+    if !CHAOS.rules.is_empty() || !CHAOS.windows.is_empty() {
+        info!(rules = CHAOS.rules.len(), windows = CHAOS.windows.len(), "Loaded chaos configuration");
+    }
+    if !CHAOS.windows.is_empty() {
+        tokio::spawn(watch_windows());
+    }
+}
+
+#[derive(Serialize)]
+struct RuleSummary {
+    name: String,
+    faults: Vec<&'static str>
+}
+
+async fn chaos_state() -> Result<impl Reply, Infallible> {
+    let now = Utc::now();
+    let rules: Vec<RuleSummary> = CHAOS.rules.iter()
+        .map(|rule| RuleSummary {
+            name: rule.label(),
+            faults: [
+                rule.error.as_ref().map(|_| "error"),
+                rule.latency.as_ref().map(|_| "latency"),
+                rule.abort.as_ref().map(|_| "abort"),
+                rule.truncate.as_ref().map(|_| "truncate")
+            ].into_iter().flatten().collect()
+        })
+        .collect();
+    let windows: Vec<WindowState> = CHAOS.windows.iter().map(|window| window.state(now)).collect();
+    Ok(warp::reply::json(&serde_json::json!({
+        "rules": rules,
+        "windows": windows
+    })))
+}
+
+pub fn chaos_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(warp::get())
+        .and_then(chaos_state)
+        .boxed()
+}
+
+/// Wraps the routes so the requests matching a chaos rule get its faults:
+/// latency before the request is handled, an error status instead of
+/// handling it, or a response body that is cut short.
+pub fn wrap<F, R>(filter: F) -> BoxedFilter<(Response,)>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply + 'static
+{
+    warp::method()
+        .and(warp::path::full())
+        .and_then(plan)
+        .and(filter)
+        .map(|plan: Plan, reply: R| plan.apply(reply.into_response()))
+        .recover(injected_error)
+        .unify()
+        .boxed()
+}
@@ -0,0 +1,246 @@
+use std::convert::Infallible;
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::{Compression, read::{GzDecoder, ZlibDecoder}, write::{GzEncoder, ZlibEncoder}};
+use futures::{StreamExt, stream};
+use warp::{Filter, Rejection, Reply, filters::BoxedFilter, http::HeaderValue, hyper::{HeaderMap, StatusCode}, reply::Response};
+
+use crate::{api::IncomingRequest, chaos, echo, history::HISTORY, server::{self, BodyStream}};
+
+// Decoded request bodies may not be larger than this, so a small upload can't
+// blow up into something that doesn't fit in memory.
+const MAX_DECODED_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// A content coding the server can compress responses with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate
+}
+
+impl Encoding {
+    // In order of preference, when the client likes several equally.
+    const ALL: [Encoding; 4] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip, Encoding::Deflate];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate"
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None
+        }
+    }
+
+    /// Picks the encoding the client prefers from its `Accept-Encoding`
+    /// header, or `None` when it accepts none of them.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        let mut wildcard = None;
+        let mut weights: Vec<(Encoding, f32)> = Vec::new();
+        for value in headers.get_all("accept-encoding").iter().filter_map(|v| v.to_str().ok()) {
+            for item in value.split(',') {
+                let mut params = item.split(';');
+                let name = params.next().unwrap_or_default().trim();
+                let quality = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                if name == "*" {
+                    wildcard = Some(quality);
+                } else if let Some(encoding) = Encoding::from_name(name) {
+                    weights.push((encoding, quality));
+                }
+            }
+        }
+        let quality = |encoding: Encoding| weights.iter()
+            .find(|(e, _)| *e == encoding)
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        Encoding::ALL.into_iter()
+            .filter(|e| quality(*e) > 0.0)
+            // Stable, so ties keep the order of preference.
+            .max_by(|a, b| quality(*a).total_cmp(&quality(*b)).then(std::cmp::Ordering::Greater))
+    }
+}
+
+enum Encoder {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>)
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            Encoding::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::default()))
+        })
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Brotli(encoder) => encoder.as_mut(),
+            Encoder::Zstd(encoder) => encoder,
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Deflate(encoder) => encoder
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+            Encoder::Gzip(encoder) => encoder.get_mut(),
+            Encoder::Deflate(encoder) => encoder.get_mut()
+        }
+    }
+
+    // Compresses the chunk and flushes, so that streamed responses arrive as
+    // they are produced rather than when the compressor's buffer fills up.
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        self.writer().write_all(chunk)?;
+        self.writer().flush()?;
+        Ok(Bytes::from(std::mem::take(self.output())))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::Brotli(encoder) => encoder.into_inner(),
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Deflate(encoder) => encoder.finish()?
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+fn compress_stream(body: BodyStream, encoder: Encoder) -> BodyStream {
+    stream::unfold(Some((body, encoder)), |state| async move {
+        let (mut body, mut encoder) = state?;
+        loop {
+            match body.next().await {
+                Some(Ok(chunk)) => match encoder.encode(&chunk) {
+                    Ok(compressed) if compressed.is_empty() => continue,
+                    Ok(compressed) => return Some((Ok(compressed), Some((body, encoder)))),
+                    Err(error) => return Some((Err(error.into()), None))
+                },
+                Some(Err(error)) => return Some((Err(error), None)),
+                None => return match encoder.finish() {
+                    Ok(rest) => Some((Ok(rest), None)),
+                    Err(error) => Some((Err(error.into()), None))
+                }
+            }
+        }
+    }).boxed()
+}
+
+/// Compresses the body of the response with the given encoding.
+pub fn encode(mut response: Response, encoding: Encoding) -> Response {
+    let Ok(encoder) = Encoder::new(encoding) else {
+        return response;
+    };
+    let headers = response.headers_mut();
+    headers.remove("content-length");
+    headers.insert("content-encoding", HeaderValue::from_static(encoding.name()));
+    headers.append("vary", HeaderValue::from_static("accept-encoding"));
+    server::map_body(response, |body| compress_stream(body, encoder))
+}
+
+// Responses that have no body to compress, are compressed already, would
+// stop streaming events as they happen, or are cut short by chaos.
+fn is_compressible(response: &Response) -> bool {
+    let status = response.status();
+    let is_event_stream = response.headers().get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    let no_body = status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || server::body_length(response) == Some(0);
+    let is_cut = response.extensions().get::<chaos::Cut>().is_some();
+    !no_body && !is_event_stream && !is_cut && !response.headers().contains_key("content-encoding")
+}
+
+/// Wraps the routes to compress their responses with the encoding the
+/// client prefers.
+pub fn wrap<F, R>(filter: F) -> BoxedFilter<(Response,)>
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply
+{
+    warp::header::headers_cloned()
+        .and(filter)
+        .map(|headers: HeaderMap, reply: R| {
+            let response = reply.into_response();
+            match Encoding::negotiate(&headers).filter(|_| is_compressible(&response)) {
+                Some(encoding) => encode(response, encoding),
+                None => response
+            }
+        })
+        .boxed()
+}
+
+/// Echoes the request with the response compressed with the given
+/// encoding, whatever the client accepts.
+pub fn compressed_handler(encoding: Encoding) -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(echo::incoming_request())
+        .and_then(move |request: IncomingRequest| async move {
+            let response = echo::controlled(request, StatusCode::OK, &HISTORY).await;
+            Ok::<_, Infallible>(encode(response, encoding))
+        })
+        .boxed()
+}
+
+/// Undoes the `Content-Encoding` of a request body. Returns the codings that
+/// were applied along with the decoded body.
+pub fn decode(body: &[u8], headers: &HeaderMap) -> Result<Option<(String, Vec<u8>)>, String> {
+    let codings: Vec<String> = headers.get_all("content-encoding").iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty() && v != "identity")
+        .collect();
+    if codings.is_empty() {
+        return Ok(None);
+    }
+    let mut decoded = body.to_vec();
+    // Codings are listed in the order they were applied.
+    for coding in codings.iter().rev() {
+        let encoding = Encoding::from_name(coding).ok_or_else(|| format!("unsupported content encoding {}", coding))?;
+        let input = decoded.as_slice();
+        let result = match encoding {
+            Encoding::Gzip => read_limited(GzDecoder::new(input)),
+            Encoding::Deflate => read_limited(ZlibDecoder::new(input)),
+            Encoding::Brotli => read_limited(brotli::Decompressor::new(input, 4096)),
+            Encoding::Zstd => zstd::stream::read::Decoder::new(input).and_then(read_limited)
+        };
+        decoded = result.map_err(|e| format!("invalid {} body: {}", coding, e))?;
+    }
+    Ok(Some((codings.join(", "), decoded)))
+}
+
+fn read_limited(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    reader.take(MAX_DECODED_LENGTH + 1).read_to_end(&mut output)?;
+    if output.len() as u64 > MAX_DECODED_LENGTH {
+        return Err(io::Error::other(format!("decoded body is larger than {} bytes", MAX_DECODED_LENGTH)));
+    }
+    Ok(output)
+}
@@ -0,0 +1,27 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+/// Loads a configuration file. Files ending in `.json` are read as JSON,
+/// anything else as YAML.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let is_json = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    if is_json {
+        serde_json::from_str(&content).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    } else {
+        serde_yaml::from_str(&content).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+}
+
+/// Loads the configuration file named by the given environment variable, or
+/// returns `None` when the variable isn't set. An unreadable or invalid file
+/// is fatal, as the server would otherwise quietly run without it.
+pub fn load_from_env<T: DeserializeOwned>(variable: &str) -> Option<T> {
+    let path = std::env::var(variable).ok().filter(|p| !p.is_empty())?;
+    match load(Path::new(&path)) {
+        Ok(config) => Some(config),
+        Err(error) => panic!("{}: {}", variable, error)
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use tracing::*;
+use warp::{http::{HeaderName, HeaderValue}, hyper::{HeaderMap, StatusCode}, reply::Response};
+
+// Upper bound for a requested delay so a single request can't tie up a connection forever.
+pub const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Response overrides requested by the caller, either through `echo_*` query
+/// parameters or the equivalent `X-Echo-*` request headers. Query parameters
+/// take precedence over headers.
+#[derive(Debug, Default)]
+pub struct EchoControl {
+    status: Option<StatusCode>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Option<String>,
+    delay: Option<Duration>
+}
+
+impl EchoControl {
+    pub fn new(query: &str, headers: &HeaderMap) -> Self {
+        let mut control = EchoControl::default();
+
+        let from_headers = |name: &str| headers.get_all(name).iter()
+            .filter_map(|v| v.to_str().ok().map(str::to_string))
+            .collect::<Vec<_>>();
+        control.set("code", from_headers("x-echo-code"));
+        control.set("header", from_headers("x-echo-header"));
+        control.set("body", from_headers("x-echo-body"));
+        control.set("delay", from_headers("x-echo-delay"));
+
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+        for key in ["code", "header", "body", "delay"] {
+            let name = format!("echo_{}", key);
+            let values = params.iter()
+                .filter(|(k, _)| *k == name)
+                .map(|(_, v)| v.clone())
+                .collect();
+            control.set(key, values);
+        }
+        control
+    }
+
+    fn set(&mut self, key: &str, values: Vec<String>) {
+        if values.is_empty() {
+            return;
+        }
+        match key {
+            "code" => {
+                let value = values.last().unwrap();
+                match value.trim().parse::<u16>().ok().and_then(|c| StatusCode::from_u16(c).ok()) {
+                    Some(status) => self.status = Some(status),
+                    None => warn!(value, "ignoring invalid echo status code")
+                }
+            },
+            "header" => {
+                self.headers = values.iter().filter_map(|value| {
+                    let parsed = value.split_once(':').and_then(|(name, value)| {
+                        let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+                        let value = HeaderValue::from_str(value.trim()).ok()?;
+                        Some((name, value))
+                    });
+                    if parsed.is_none() {
+                        warn!(value, "ignoring invalid echo header");
+                    }
+                    parsed
+                }).collect();
+            },
+            "body" => self.body = values.last().cloned(),
+            "delay" => {
+                let value = values.last().unwrap();
+                match parse_delay(value) {
+                    Some(delay) => self.delay = Some(delay.min(MAX_DELAY)),
+                    None => warn!(value, "ignoring invalid echo delay")
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status
+    }
+
+    /// Waits for the requested delay, if any.
+    pub async fn delay(&self) {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Applies the requested body and headers to an already built response.
+    pub fn apply(self, mut response: Response) -> Response {
+        if let Some(body) = self.body {
+            *response.body_mut() = body.into();
+            response.headers_mut().insert("content-type", HeaderValue::from_static("text/plain; charset=utf-8"));
+        }
+        for (name, value) in self.headers {
+            response.headers_mut().append(name, value);
+        }
+        response
+    }
+}
+
+// Delays are given in milliseconds, optionally suffixed with `ms` or `s`.
+fn parse_delay(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Some(secs) = value.strip_suffix("ms") {
+        secs.trim().parse::<u64>().ok().map(Duration::from_millis)
+    } else if let Some(secs) = value.strip_suffix('s') {
+        secs.trim().parse::<f64>().ok()
+            .filter(|s| s.is_finite() && *s >= 0.0)
+            // Clamped first, as seconds past what a Duration holds would panic.
+            .map(|s| Duration::from_secs_f64(s.min(MAX_DELAY.as_secs_f64())))
+    } else {
+        value.parse::<u64>().ok().map(Duration::from_millis)
+    }
+}
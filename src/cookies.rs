@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+
+use warp::{Filter, Reply, filters::BoxedFilter, http::HeaderValue, hyper::{HeaderMap, StatusCode}, reply::Response};
+
+use crate::{api::IncomingRequest, echo};
+
+// Query parameters starting with this set the attributes of the cookies
+// rather than naming one, like `cookie_path=/` or `cookie_samesite=Lax`.
+const ATTRIBUTE_PREFIX: &str = "cookie_";
+
+/// The cookies sent in the `Cookie` headers. When a name is sent twice the
+/// first one wins, as browsers send the cookie with the most specific path
+/// first.
+pub fn parse(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut cookies = BTreeMap::new();
+    let pairs = headers.get_all("cookie").iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.split_once('='));
+    for (name, value) in pairs {
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        cookies.entry(name.to_string()).or_insert_with(|| value.to_string());
+    }
+    cookies
+}
+
+/// The attributes of the `Set-Cookie` headers, from the `cookie_domain`,
+/// `cookie_path`, `cookie_samesite`, `cookie_secure`, `cookie_httponly`,
+/// `cookie_max_age` and `cookie_partitioned` query parameters.
+#[derive(Default)]
+struct Attributes {
+    domain: Option<String>,
+    path: Option<String>,
+    same_site: Option<&'static str>,
+    secure: bool,
+    http_only: bool,
+    max_age: Option<i64>,
+    partitioned: bool
+}
+
+// A flag is set by naming it, or giving it a true value.
+fn parse_flag(name: &str, value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "" | "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => Err(format!("Invalid {}{} {}", ATTRIBUTE_PREFIX, name, value))
+    }
+}
+
+impl Attributes {
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "domain" => self.domain = Some(attribute_value(name, value)?),
+            "path" => self.path = Some(attribute_value(name, value)?),
+            "samesite" => self.same_site = Some(match value.to_ascii_lowercase().as_str() {
+                "strict" => "Strict",
+                "lax" => "Lax",
+                "none" => "None",
+                _ => return Err(format!("Invalid {}samesite {}, expected Strict, Lax or None", ATTRIBUTE_PREFIX, value))
+            }),
+            "secure" => self.secure = parse_flag(name, value)?,
+            "httponly" => self.http_only = parse_flag(name, value)?,
+            "max_age" => self.max_age = Some(value.parse()
+                .map_err(|_| format!("Invalid {}max_age {}", ATTRIBUTE_PREFIX, value))?),
+            "partitioned" => self.partitioned = parse_flag(name, value)?,
+            _ => return Err(format!("Unknown cookie attribute {}{}", ATTRIBUTE_PREFIX, name))
+        }
+        Ok(())
+    }
+
+    fn append_to(&self, cookie: &mut String) {
+        if let Some(domain) = &self.domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        // Without a path the browser would scope the cookie to /cookies.
+        cookie.push_str(&format!("; Path={}", self.path.as_deref().unwrap_or("/")));
+        if let Some(same_site) = self.same_site {
+            cookie.push_str(&format!("; SameSite={}", same_site));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if self.partitioned {
+            cookie.push_str("; Partitioned");
+        }
+    }
+}
+
+fn attribute_value(name: &str, value: &str) -> Result<String, String> {
+    if value.is_empty() || value.chars().any(|c| c == ';' || c.is_control()) {
+        return Err(format!("Invalid {}{} {}", ATTRIBUTE_PREFIX, name, value));
+    }
+    Ok(value.to_string())
+}
+
+// Cookie names are tokens, and values may not hold spaces, quotes, commas,
+// semicolons or backslashes.
+fn validate(name: &str, value: &str) -> Result<(), String> {
+    let is_token = !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b));
+    if !is_token {
+        return Err(format!("Invalid cookie name {}", name));
+    }
+    if !value.bytes().all(|b| b.is_ascii_graphic() && !b"\",;\\".contains(&b)) {
+        return Err(format!("Invalid value for cookie {}", name));
+    }
+    Ok(())
+}
+
+// Splits the query into the cookies it names and their attributes.
+fn parse_query(query: &str) -> Result<(Vec<(String, String)>, Attributes), String> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query)
+        .map_err(|e| format!("Invalid query: {}", e))?;
+    let mut attributes = Attributes::default();
+    let mut cookies = Vec::new();
+    for (name, value) in pairs {
+        match name.strip_prefix(ATTRIBUTE_PREFIX) {
+            Some(attribute) => attributes.set(attribute, &value)?,
+            None => cookies.push((name, value))
+        }
+    }
+    if cookies.is_empty() {
+        return Err("No cookies named in the query".to_string());
+    }
+    Ok((cookies, attributes))
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status
+    ).into_response()
+}
+
+// Sends the client to the cookie list, with the given `Set-Cookie` headers.
+fn redirect(set_cookies: Vec<String>) -> Response {
+    let mut response = warp::reply::with_status(warp::reply(), StatusCode::FOUND).into_response();
+    response.headers_mut().insert("location", HeaderValue::from_static("/cookies"));
+    for cookie in set_cookies {
+        match HeaderValue::from_str(&cookie) {
+            Ok(value) => { response.headers_mut().append("set-cookie", value); },
+            Err(_) => return error(StatusCode::BAD_REQUEST, format!("Invalid cookie {}", cookie))
+        }
+    }
+    response
+}
+
+/// Sets the cookies named in the query, then redirects to `/cookies`.
+async fn set(request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let (cookies, attributes) = match parse_query(&request.query) {
+        Ok(parsed) => parsed,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let mut set_cookies = Vec::new();
+    for (name, value) in cookies {
+        if let Err(message) = validate(&name, &value) {
+            return Ok(error(StatusCode::BAD_REQUEST, message));
+        }
+        let mut cookie = format!("{}={}", name, value);
+        attributes.append_to(&mut cookie);
+        if let Some(max_age) = attributes.max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        set_cookies.push(cookie);
+    }
+    Ok(redirect(set_cookies))
+}
+
+/// Expires the cookies named in the query, then redirects to `/cookies`.
+/// They are only removed when the domain and path match those they were set
+/// with, and a partitioned cookie only when `cookie_partitioned` is given.
+async fn delete(request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let (cookies, attributes) = match parse_query(&request.query) {
+        Ok(parsed) => parsed,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    // These would be overridden by the expiry, or make no difference to which cookie goes.
+    if attributes.max_age.is_some() || attributes.same_site.is_some() {
+        return Ok(error(
+            StatusCode::BAD_REQUEST,
+            format!("{0}max_age and {0}samesite can't be used to delete cookies", ATTRIBUTE_PREFIX)
+        ));
+    }
+    let mut set_cookies = Vec::new();
+    for (name, _) in cookies {
+        if let Err(message) = validate(&name, "") {
+            return Ok(error(StatusCode::BAD_REQUEST, message));
+        }
+        let mut cookie = format!("{}=", name);
+        attributes.append_to(&mut cookie);
+        cookie.push_str("; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT");
+        set_cookies.push(cookie);
+    }
+    Ok(redirect(set_cookies))
+}
+
+/// Lists the cookies the client sent.
+async fn list(headers: HeaderMap) -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::json(&serde_json::json!({ "cookies": parse(&headers) })))
+}
+
+pub fn cookies_handler() -> BoxedFilter<(impl Reply,)> {
+    let list = warp::path::end()
+        .and(warp::get())
+        .and(warp::header::headers_cloned())
+        .and_then(list);
+    let set = warp::path("set")
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(set);
+    let delete = warp::path("delete")
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(delete);
+    list.or(set).or(delete).boxed()
+}
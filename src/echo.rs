@@ -1,67 +1,128 @@
 use std::convert::Infallible;
+use std::sync::RwLock;
 
 use tracing::*;
 
-use bytes::Bytes;
-use warp::{Filter, Reply, filters::BoxedFilter, hyper::{HeaderMap, Method, StatusCode}, path::FullPath};
+use warp::{Filter, Reply, filters::BoxedFilter, http::Version, hyper::{HeaderMap, StatusCode}};
 
 use askama::Template;
 
-use crate::{api, metrics};
+use crate::{api::{self, IncomingRequest}, control::EchoControl, format::Format, history::{History, HISTORY}, idempotency, metrics, mock, openapi, scenario, server::{Connection, StreamId}, templating};
 
 #[tracing::instrument]
-async fn ok(method: Method, path: FullPath, headers: HeaderMap, bytes: Bytes) -> Result<impl Reply, Infallible> {
-    let reply = response(method, path, headers, bytes, StatusCode::OK);
+async fn ok(request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    if let Some(key) = idempotency::key(&request) {
+        let reply = idempotency::respond(key, request, async |request| {
+            controlled(request, StatusCode::OK, &HISTORY).await
+        }).await;
+        return Ok(reply);
+    }
+    let reply = controlled(request, StatusCode::OK, &HISTORY).await;
     Ok(reply)
 }
 
 #[tracing::instrument]
-async fn not_found(method: Method, path: FullPath, headers: HeaderMap, bytes: Bytes) -> Result<impl Reply, Infallible> {
-    let reply = response(method, path, headers, bytes, StatusCode::NOT_FOUND);
+async fn not_found(request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    if let Some(reply) = scenario::respond(&request, &HISTORY).await {
+        return Ok(reply);
+    }
+    if let Some(reply) = mock::respond(&request, &HISTORY).await {
+        return Ok(reply);
+    }
+    if let Some(reply) = openapi::respond(&request, &HISTORY).await {
+        return Ok(reply);
+    }
+    let reply = controlled(request, StatusCode::NOT_FOUND, &HISTORY).await;
     Ok(reply)
 }
 
-#[tracing::instrument]
-fn response(method: Method, path: FullPath, headers: HeaderMap, bytes: Bytes, status: StatusCode) -> impl Reply {
+/// Builds the echo response, honouring any `echo_*` overrides the caller asked
+/// for, and records the request in the given history.
+pub async fn controlled(request: IncomingRequest, status: StatusCode, history: &RwLock<History>) -> warp::reply::Response {
+    let control = EchoControl::new(&request.query, &request.headers);
+    control.delay().await;
+    let status = control.status().unwrap_or(status);
+    let format = Format::negotiate(&request.query, &request.headers);
+    let template = templating::requested(&request.query, &request.headers);
+    let reply = response(request, format, template, status, history);
+    control.apply(reply.into_response())
+}
+
+#[tracing::instrument(skip(history))]
+fn response(request: IncomingRequest, format: Format, template: Option<String>, status: StatusCode, history: &RwLock<History>) -> impl Reply {
     let metric_counter = metrics::ECHO_COUNT
-        .get_metric_with_label_values(&[method.as_str()])
+        .get_metric_with_label_values(&[request.method.as_str()])
         .unwrap();
     let server = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
-    let result = api::EchoResponse::new(method, headers, path, bytes, server);
-    let response = warp::reply::json(&result);
+    let result = api::EchoResponse::new(&request, server);
+    let response = match template {
+        Some(name) => templating::render(&name, &result, status),
+        None => warp::reply::with_status(format.reply(&result), status).into_response()
+    };
+    history.write().unwrap().record(result, response.status());
     metric_counter.inc();
-    warp::reply::with_status(response, status)
+    response
 }
 
-pub fn echo_handler() -> BoxedFilter<(impl warp::Reply,)> {
+/// Extracts everything the echo handlers report about a request.
+pub fn incoming_request() -> BoxedFilter<(IncomingRequest,)> {
     warp::method()
         .and(warp::path::full())
+        .and(query_string())
         .and(warp::header::headers_cloned())
         .and(warp::body::bytes())
+        .and(warp::ext::optional::<Version>())
+        .and(warp::ext::optional::<Connection>())
+        .and(warp::ext::optional::<StreamId>())
+        .map(|method, path, query, headers, body, version, connection: Option<Connection>, stream: Option<StreamId>| {
+            IncomingRequest {
+                method,
+                path,
+                query,
+                headers,
+                body,
+                version,
+                remote_addr: connection.as_ref().map(|c| c.remote_addr),
+                tls: connection.as_ref().and_then(|c| c.tls.clone()),
+                http2_fingerprint: connection.and_then(|c| c.http2_fingerprint),
+                stream_id: stream.map(|s| s.0)
+            }
+        })
+        .boxed()
+}
+
+pub fn echo_handler() -> BoxedFilter<(impl warp::Reply,)> {
+    incoming_request()
         .and_then(ok)
         .boxed()
 }
 
 pub fn default_handler() -> BoxedFilter<(impl warp::Reply,)> {
-    warp::method()
-        .and(warp::path::full())
-        .and(warp::header::headers_cloned())
-        .and(warp::body::bytes())
+    incoming_request()
         .and_then(not_found)
         .boxed()
 }
 
+// The raw query string, or an empty string when the request has none.
+fn query_string() -> BoxedFilter<(String,)> {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .boxed()
+}
+
 #[tracing::instrument]
 pub fn template_handler() -> BoxedFilter<(impl warp::Reply,)> {
     warp::get()
         .and(warp::header::headers_cloned())
-        .map(|headers: HeaderMap| {
+        .and(warp::ext::optional::<Connection>())
+        .map(|headers: HeaderMap, connection: Option<Connection>| {
             let metric_counter = metrics::ECHO_COUNT
                 .get_metric_with_label_values(&["GET"])
                 .unwrap();
             metric_counter.inc();
             let server = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
-            let template = api::IndexTemplate::new(headers, server).render().unwrap();
+            let template = api::IndexTemplate::new(headers, server, connection.and_then(|c| c.tls).as_ref()).render().unwrap();
             warp::reply::html(template)
         }).boxed()
 }
@@ -191,6 +191,7 @@ async fn expensive_get_handler() -> Result<impl Reply, Rejection> {
     Ok(warp::reply::html(html))
 }
 
+#[allow(clippy::manual_range_contains)]
 #[instrument]
 async fn expensive_post_handler(form: HashMap<String, String>) -> Result<impl Reply, Rejection> {
     let server = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
@@ -252,6 +253,7 @@ async fn expensive_post_handler(form: HashMap<String, String>) -> Result<impl Re
     Ok(warp::reply::html(html))
 }
 
+#[allow(clippy::manual_range_contains)]
 #[instrument]
 async fn expensive_json_handler(query: ExpensiveQuery) -> Result<impl Reply, Rejection> {
     let prime_limit = query.prime_limit;
@@ -0,0 +1,421 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use indexmap::IndexMap;
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Enough for any ClientHello, and for the start of an HTTP/2 connection up
+// to the headers of its first request.
+const RECORDING_LIMIT: usize = 16 * 1024;
+
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+lazy_static! {
+    /// Whether the TLS listener fingerprints its clients, set with the
+    /// `TLS_FINGERPRINT` environment variable.
+    pub static ref ENABLED: bool = std::env::var("TLS_FINGERPRINT")
+        .is_ok_and(|value| matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes"));
+}
+
+/// A stream that keeps a copy of the first bytes read from it, if asked to,
+/// until the recording is taken.
+pub struct Recorder<S> {
+    inner: S,
+    recording: Recording
+}
+
+/// The bytes recorded by a `Recorder`.
+#[derive(Clone, Default)]
+pub struct Recording(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl Recording {
+    /// The bytes recorded so far. Recording stops once they are taken.
+    pub fn take(&self) -> Vec<u8> {
+        self.0.lock().unwrap().take().unwrap_or_default()
+    }
+
+    /// Runs the function over the bytes recorded so far, if still recording.
+    pub fn inspect<T>(&self, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        self.0.lock().unwrap().as_deref().map(f)
+    }
+}
+
+impl<S> Recorder<S> {
+    pub fn new(inner: S, record: bool) -> Self {
+        Recorder { inner, recording: Recording(Arc::new(Mutex::new(record.then(Vec::new)))) }
+    }
+
+    pub fn recording(&self) -> Recording {
+        self.recording.clone()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            if let Some(recorded) = self.recording.0.lock().unwrap().as_mut() {
+                let read = &buf.filled()[before..];
+                let room = RECORDING_LIMIT.saturating_sub(recorded.len());
+                recorded.extend_from_slice(&read[..read.len().min(room)]);
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+/// The JA3 and JA4 fingerprints of a ClientHello.
+#[derive(Clone, Debug, Serialize)]
+pub struct TlsFingerprint {
+    pub ja3: String,
+    pub ja3_hash: String,
+    pub ja4: String
+}
+
+// GREASE values (RFC 8701) are random by design, so fingerprints leave them out.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+// A cursor over the big-endian fields of a TLS or HTTP/2 message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3).map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // A vector with a length prefix of the given number of bytes.
+    fn vector(&mut self, prefix: usize) -> Option<Reader<'a>> {
+        let length = match prefix {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?
+        };
+        self.bytes(length).map(Reader)
+    }
+
+    fn u16s(mut self) -> Vec<u16> {
+        std::iter::from_fn(|| self.u16()).collect()
+    }
+}
+
+struct ClientHello {
+    version: u16,
+    ciphers: Vec<u16>,
+    // In the order the client sent them.
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    supported_versions: Vec<u16>,
+    alpn: Option<Vec<u8>>,
+    has_server_name: bool
+}
+
+// Reassembles the handshake message from the TLS records and picks out the
+// fields the fingerprints are made of.
+fn parse_client_hello(records: &[u8]) -> Option<ClientHello> {
+    let mut handshake = Vec::new();
+    let mut records = Reader(records);
+    while let Some(content_type) = records.u8() {
+        records.u16()?;
+        let fragment = records.vector(2)?;
+        if content_type != 22 {
+            break;
+        }
+        handshake.extend_from_slice(fragment.0);
+        let mut message = Reader(&handshake);
+        if message.u8()? != 1 {
+            return None;
+        }
+        if message.u24().is_some_and(|length| message.0.len() >= length) {
+            break;
+        }
+    }
+
+    let mut message = Reader(&handshake);
+    if message.u8()? != 1 {
+        return None;
+    }
+    let mut body = message.vector(3)?;
+    let mut hello = ClientHello {
+        version: body.u16()?,
+        ciphers: Vec::new(),
+        extensions: Vec::new(),
+        groups: Vec::new(),
+        point_formats: Vec::new(),
+        signature_algorithms: Vec::new(),
+        supported_versions: Vec::new(),
+        alpn: None,
+        has_server_name: false
+    };
+    body.bytes(32)?;
+    body.vector(1)?;
+    hello.ciphers = body.vector(2)?.u16s();
+    body.vector(1)?;
+    let mut extensions = match body.vector(2) {
+        Some(extensions) => extensions,
+        None => return Some(hello)
+    };
+    while let Some(extension) = extensions.u16() {
+        let mut data = extensions.vector(2)?;
+        hello.extensions.push(extension);
+        match extension {
+            0x0000 => hello.has_server_name = true,
+            0x000a => hello.groups = data.vector(2)?.u16s(),
+            0x000b => hello.point_formats = data.vector(1)?.0.to_vec(),
+            0x000d => hello.signature_algorithms = data.vector(2)?.u16s(),
+            0x0010 => hello.alpn = data.vector(2)?.vector(1).map(|protocol| protocol.0.to_vec()),
+            0x002b => hello.supported_versions = data.vector(1)?.u16s(),
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    values.iter().map(T::to_string).collect::<Vec<_>>().join(separator)
+}
+
+fn without_grease(values: &[u16]) -> Vec<u16> {
+    values.iter().copied().filter(|v| !is_grease(*v)).collect()
+}
+
+// The first 12 hex digits of the SHA-256 of the list, as JA4 has it.
+fn truncated_hash(list: &str) -> String {
+    if list.is_empty() {
+        return "000000000000".to_string();
+    }
+    format!("{:x}", Sha256::digest(list.as_bytes()))[..12].to_string()
+}
+
+fn hex_list(values: &[u16]) -> String {
+    join(&values.iter().map(|v| format!("{:04x}", v)).collect::<Vec<_>>(), ",")
+}
+
+fn ja3(hello: &ClientHello) -> String {
+    format!(
+        "{},{},{},{},{}",
+        hello.version,
+        join(&without_grease(&hello.ciphers), "-"),
+        join(&without_grease(&hello.extensions), "-"),
+        join(&without_grease(&hello.groups), "-"),
+        join(&hello.point_formats, "-")
+    )
+}
+
+fn ja4(hello: &ClientHello) -> String {
+    let version = without_grease(&hello.supported_versions).into_iter().max().unwrap_or(hello.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        0xfeff => "d1",
+        0xfefd => "d2",
+        0xfefc => "d3",
+        _ => "00"
+    };
+    let ciphers = without_grease(&hello.ciphers);
+    let extensions = without_grease(&hello.extensions);
+    let alpn = match hello.alpn.as_deref() {
+        Some([first, .., last]) if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() => {
+            format!("{}{}", *first as char, *last as char)
+        },
+        Some([single]) if single.is_ascii_alphanumeric() => format!("{}{}", *single as char, *single as char),
+        Some(protocol @ [first, ..]) => {
+            let last = protocol.last().unwrap_or(first);
+            format!("{}{}", &format!("{:02x}", first)[..1], &format!("{:02x}", last)[1..])
+        },
+        _ => "00".to_string()
+    };
+    let a = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        if hello.has_server_name { 'd' } else { 'i' },
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn
+    );
+
+    let mut sorted_ciphers = ciphers;
+    sorted_ciphers.sort_unstable();
+    let b = truncated_hash(&hex_list(&sorted_ciphers));
+
+    // The server name and ALPN are already in the first part.
+    let mut sorted_extensions: Vec<u16> = extensions.into_iter().filter(|e| *e != 0x0000 && *e != 0x0010).collect();
+    sorted_extensions.sort_unstable();
+    let mut c = hex_list(&sorted_extensions);
+    let signature_algorithms = without_grease(&hello.signature_algorithms);
+    if !signature_algorithms.is_empty() {
+        c.push('_');
+        c.push_str(&hex_list(&signature_algorithms));
+    }
+    format!("{}_{}_{}", a, b, truncated_hash(&c))
+}
+
+impl TlsFingerprint {
+    /// Fingerprints the ClientHello at the start of the bytes a client sent.
+    pub fn new(records: &[u8]) -> Option<Self> {
+        let hello = parse_client_hello(records)?;
+        let ja3 = ja3(&hello);
+        Some(TlsFingerprint {
+            ja3_hash: format!("{:x}", Md5::digest(ja3.as_bytes())),
+            ja3,
+            ja4: ja4(&hello)
+        })
+    }
+}
+
+/// What an HTTP/2 client sends before its first request, summed up in the
+/// format popularised by Akamai.
+#[derive(Clone, Debug, Serialize)]
+pub struct Http2Fingerprint {
+    // In the order the client sent them.
+    pub settings: IndexMap<String, u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_update: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub priorities: Vec<String>,
+    pub pseudo_header_order: Vec<String>,
+    pub akamai: String
+}
+
+impl Http2Fingerprint {
+    /// Fingerprints the start of an HTTP/2 connection. Returns `None` until
+    /// the headers of the first request have arrived.
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut frames = Reader(bytes.strip_prefix(HTTP2_PREFACE)?);
+        let mut settings = Vec::new();
+        let mut window_update = None;
+        let mut priorities = Vec::new();
+        let mut header_block = Vec::new();
+        loop {
+            let length = frames.u24()?;
+            let frame_type = frames.u8()?;
+            let flags = frames.u8()?;
+            let stream_id = frames.u32()? & 0x7fff_ffff;
+            let mut payload = Reader(frames.bytes(length)?);
+            match frame_type {
+                // SETTINGS, unless it acknowledges the server's.
+                0x4 if flags & 0x1 == 0 => {
+                    while let (Some(id), Some(value)) = (payload.u16(), payload.u32()) {
+                        settings.push((id, value));
+                    }
+                },
+                0x8 if stream_id == 0 => window_update = payload.u32().map(|increment| increment & 0x7fff_ffff),
+                0x2 => priorities.push(priority(stream_id, &mut payload)?),
+                0x1 | 0x9 => {
+                    if frame_type == 0x1 {
+                        let padding = if flags & 0x8 != 0 { payload.u8()? as usize } else { 0 };
+                        if flags & 0x20 != 0 {
+                            priorities.push(priority(stream_id, &mut payload)?);
+                        }
+                        let content = payload.0.len().checked_sub(padding)?;
+                        header_block.extend_from_slice(&payload.0[..content]);
+                    } else {
+                        header_block.extend_from_slice(payload.0);
+                    }
+                    // END_HEADERS
+                    if flags & 0x4 != 0 {
+                        break;
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let mut pseudo_header_order = Vec::new();
+        loona_hpack::Decoder::new()
+            .decode_with_cb(&header_block, |name, _| {
+                if name.starts_with(b":") {
+                    pseudo_header_order.push(String::from_utf8_lossy(&name).to_string());
+                }
+            })
+            .ok()?;
+
+        let akamai = format!(
+            "{}|{}|{}|{}",
+            settings.iter().map(|(id, value)| format!("{}:{}", id, value)).collect::<Vec<_>>().join(";"),
+            window_update.map(|w| w.to_string()).unwrap_or_else(|| "00".to_string()),
+            if priorities.is_empty() { "0".to_string() } else { priorities.join(",") },
+            pseudo_header_order.iter().filter_map(|name| name.get(1..2)).collect::<Vec<_>>().join(",")
+        );
+        let settings = settings.into_iter().map(|(id, value)| (setting_name(id), value)).collect();
+        Some(Http2Fingerprint { settings, window_update, priorities, pseudo_header_order, akamai })
+    }
+}
+
+fn setting_name(id: u16) -> String {
+    let name = match id {
+        0x1 => "HEADER_TABLE_SIZE",
+        0x2 => "ENABLE_PUSH",
+        0x3 => "MAX_CONCURRENT_STREAMS",
+        0x4 => "INITIAL_WINDOW_SIZE",
+        0x5 => "MAX_FRAME_SIZE",
+        0x6 => "MAX_HEADER_LIST_SIZE",
+        0x8 => "ENABLE_CONNECT_PROTOCOL",
+        0x9 => "NO_RFC7540_PRIORITIES",
+        id => return id.to_string()
+    };
+    name.to_string()
+}
+
+// A priority as `stream:exclusive:dependency:weight`.
+fn priority(stream_id: u32, payload: &mut Reader) -> Option<String> {
+    let dependency = payload.u32()?;
+    let weight = payload.u8()? as u16 + 1;
+    Some(format!("{}:{}:{}:{}", stream_id, dependency >> 31, dependency & 0x7fff_ffff, weight))
+}
@@ -0,0 +1,200 @@
+use askama::Template;
+use quick_xml::{events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event}, Writer};
+use serde_json::Value;
+use tracing::*;
+use warp::{http::HeaderValue, hyper::{HeaderMap, StatusCode}, reply::Response, Reply};
+
+use crate::api::{EchoResponse, IndexTemplate};
+
+/// The formats the echo can be returned in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    PrettyJson,
+    Yaml,
+    Xml,
+    MessagePack,
+    Cbor,
+    Text,
+    Html
+}
+
+impl Format {
+    /// Picks the format from the `format` query parameter, or failing that
+    /// from the `Accept` header. Defaults to compact JSON.
+    pub fn negotiate(query: &str, headers: &HeaderMap) -> Self {
+        let params: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+        let requested = params.iter()
+            .rev()
+            .find(|(k, _)| k == "format")
+            .and_then(|(_, v)| {
+                let format = Format::from_name(v);
+                if format.is_none() {
+                    warn!(format = v, "ignoring unknown echo format");
+                }
+                format
+            });
+        requested
+            .or_else(|| headers.get("accept").and_then(|v| v.to_str().ok()).and_then(Format::from_accept))
+            .unwrap_or(Format::Json)
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "pretty" | "json-pretty" => Some(Format::PrettyJson),
+            "yaml" | "yml" => Some(Format::Yaml),
+            "xml" => Some(Format::Xml),
+            "msgpack" | "messagepack" => Some(Format::MessagePack),
+            "cbor" => Some(Format::Cbor),
+            "text" | "txt" | "plain" => Some(Format::Text),
+            "html" => Some(Format::Html),
+            _ => None
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type {
+            "application/json" | "text/json" | "*/*" | "application/*" => Some(Format::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => Some(Format::Yaml),
+            "application/xml" | "text/xml" => Some(Format::Xml),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(Format::MessagePack),
+            "application/cbor" => Some(Format::Cbor),
+            "text/plain" => Some(Format::Text),
+            "text/html" | "application/xhtml+xml" => Some(Format::Html),
+            _ => None
+        }
+    }
+
+    // Picks the supported media type with the highest quality, keeping the
+    // order of the header for media types with equal quality.
+    fn from_accept(accept: &str) -> Option<Self> {
+        let mut ranges: Vec<(f32, Format)> = accept.split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';');
+                let media_type = params.next()?.trim().to_ascii_lowercase();
+                let quality = params
+                    .filter_map(|p| p.split_once('='))
+                    .find(|(name, _)| name.trim() == "q")
+                    .and_then(|(_, q)| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Format::from_media_type(&media_type)
+                    .filter(|_| quality > 0.0)
+                    .map(|format| (quality, format))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ranges.first().map(|(_, format)| *format)
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json | Format::PrettyJson => "application/json",
+            Format::Yaml => "application/yaml",
+            Format::Xml => "application/xml",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+            Format::Text => "text/plain; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8"
+        }
+    }
+
+    /// Serializes the echo in this format.
+    pub fn reply(&self, echo: &EchoResponse) -> Response {
+        let body = match self.serialize(echo) {
+            Ok(body) => body,
+            Err(error) => {
+                error!(%error, format = ?self, "failed to serialize echo");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        let mut response = Response::new(body.into());
+        let headers = response.headers_mut();
+        headers.insert("content-type", HeaderValue::from_static(self.content_type()));
+        headers.insert("vary", HeaderValue::from_static("accept"));
+        response
+    }
+
+    fn serialize(&self, echo: &EchoResponse) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(echo).map_err(|e| e.to_string()),
+            Format::PrettyJson => serde_json::to_vec_pretty(echo).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::to_string(echo).map(String::into_bytes).map_err(|e| e.to_string()),
+            Format::Xml => {
+                let value = serde_json::to_value(echo).map_err(|e| e.to_string())?;
+                to_xml(&value).map_err(|e| e.to_string())
+            },
+            Format::MessagePack => rmp_serde::to_vec_named(echo).map_err(|e| e.to_string()),
+            Format::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(echo, &mut buffer).map_err(|e| e.to_string())?;
+                Ok(buffer)
+            },
+            Format::Text => Ok(echo.to_text().into_bytes()),
+            Format::Html => IndexTemplate::from_echo(echo).render()
+                .map(String::into_bytes)
+                .map_err(|e| e.to_string())
+        }
+    }
+}
+
+// Writes the value as an `<echo>` document. Object keys become element names
+// (or `<entry key="...">` when they aren't valid names), array items become
+// `<item>` elements, and `[name, value]` pairs such as the headers become
+// `<item name="...">value</item>`.
+fn to_xml(value: &Value) -> std::io::Result<Vec<u8>> {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    write_element(&mut writer, BytesStart::new("echo"), value)?;
+    Ok(writer.into_inner())
+}
+
+fn write_element(writer: &mut Writer<Vec<u8>>, start: BytesStart, value: &Value) -> std::io::Result<()> {
+    let end = BytesEnd::new(String::from_utf8_lossy(start.name().as_ref()).to_string());
+    match value {
+        Value::Null => {
+            writer.write_event(Event::Empty(start))?;
+            return Ok(());
+        },
+        Value::Object(map) => {
+            writer.write_event(Event::Start(start))?;
+            for (key, value) in map {
+                let element = if is_xml_name(key) {
+                    BytesStart::new(key.as_str())
+                } else {
+                    BytesStart::new("entry").with_attributes([("key", key.as_str())])
+                };
+                write_element(writer, element, value)?;
+            }
+        },
+        Value::Array(items) => {
+            writer.write_event(Event::Start(start))?;
+            for item in items {
+                match item.as_array().map(Vec::as_slice) {
+                    Some([Value::String(name), value]) => {
+                        let element = BytesStart::new("item").with_attributes([("name", name.as_str())]);
+                        write_element(writer, element, value)?;
+                    },
+                    _ => write_element(writer, BytesStart::new("item"), item)?
+                }
+            }
+        },
+        Value::String(text) => {
+            writer.write_event(Event::Start(start))?;
+            writer.write_event(Event::Text(BytesText::new(text)))?;
+        },
+        other => {
+            writer.write_event(Event::Start(start))?;
+            writer.write_event(Event::Text(BytesText::new(&other.to_string())))?;
+        }
+    }
+    writer.write_event(Event::End(end))?;
+    Ok(())
+}
+
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        && !name.to_ascii_lowercase().starts_with("xml")
+}
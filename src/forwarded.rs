@@ -0,0 +1,82 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use warp::hyper::HeaderMap;
+
+lazy_static! {
+    /// Proxies whose `Forwarded`/`X-Forwarded-For` headers are believed, taken
+    /// from the comma separated `TRUSTED_PROXIES` environment variable. Entries
+    /// can be single addresses or CIDR ranges.
+    static ref TRUSTED_PROXIES: Vec<IpNet> = std::env::var("TRUSTED_PROXIES")
+        .map(|value| parse_proxies(&value))
+        .unwrap_or_default();
+}
+
+fn parse_proxies(value: &str) -> Vec<IpNet> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            IpNet::from_str(entry).ok()
+                .or_else(|| IpAddr::from_str(entry).ok().map(IpNet::from))
+                .or_else(|| {
+                    tracing::warn!(entry, "ignoring invalid trusted proxy");
+                    None
+                })
+        })
+        .collect()
+}
+
+fn is_trusted(ip: &IpAddr) -> bool {
+    TRUSTED_PROXIES.iter().any(|net| net.contains(ip))
+}
+
+/// Works out the address of the client that originated the request. Starting
+/// from the peer address, the forwarding chain is walked from right to left for
+/// as long as the hop that reported it is a trusted proxy.
+pub fn client_ip(remote: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let mut client = remote;
+    for hop in forwarding_chain(headers).iter().rev() {
+        if !is_trusted(&client) {
+            break;
+        }
+        match hop {
+            Some(ip) => client = *ip,
+            // An obfuscated or unknown hop: we can't see past it.
+            None => break
+        }
+    }
+    client
+}
+
+// The addresses in the `Forwarded` header, or in `X-Forwarded-For` when there
+// is no `Forwarded` header, from the original client to the last proxy.
+fn forwarding_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = headers.get_all("forwarded").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element.split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_node(value))
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers.get_all("x-forwarded-for").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+// Parses a node such as `192.0.2.1`, `"192.0.2.1:8080"` or `"[2001:db8::1]:4711"`.
+fn parse_node(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    IpAddr::from_str(value).ok()
+        .or_else(|| SocketAddr::from_str(value).ok().map(|addr| addr.ip()))
+        .or_else(|| IpAddr::from_str(value.trim_start_matches('[').trim_end_matches(']')).ok())
+}
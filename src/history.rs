@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply, filters::BoxedFilter, hyper::StatusCode};
+
+use crate::api::EchoResponse;
+
+const DEFAULT_HISTORY_SIZE: usize = 100;
+
+lazy_static! {
+    /// The most recent requests handled by the echo handlers. The number of
+    /// requests kept is set with the `HISTORY_SIZE` environment variable.
+    pub static ref HISTORY: RwLock<History> = RwLock::new(History::new(
+        std::env::var("HISTORY_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_HISTORY_SIZE)
+    ));
+}
+
+/// A request as captured in the history, along with the status it was answered with.
+#[derive(Clone, Serialize)]
+pub struct Entry {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    pub status: u16,
+    pub request: EchoResponse
+}
+
+/// Criteria for listing captured requests. All given criteria must match.
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    path_prefix: Option<String>,
+    method: Option<String>,
+    // Either a header name, or `name:value` to match the value as well.
+    header: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: Option<usize>
+}
+
+impl HistoryQuery {
+    fn matches(&self, entry: &Entry) -> bool {
+        let request = &entry.request;
+        self.path_prefix.as_ref().is_none_or(|prefix| request.path().starts_with(prefix.as_str()))
+            && self.method.as_ref().is_none_or(|method| request.method().eq_ignore_ascii_case(method))
+            && self.header.as_ref().is_none_or(|header| {
+                let (name, value) = match header.split_once(':') {
+                    Some((name, value)) => (name.trim(), Some(value.trim())),
+                    None => (header.trim(), None)
+                };
+                request.headers().iter().any(|(n, v)| {
+                    n.eq_ignore_ascii_case(name) && value.is_none_or(|value| v == value)
+                })
+            })
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+    }
+}
+
+/// A ring buffer of captured requests, oldest first.
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<Entry>,
+    // When the most recent request that no longer fits was received.
+    last_dropped: Option<DateTime<Utc>>
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            entries: VecDeque::new(),
+            last_dropped: None
+        }
+    }
+
+    pub fn record(&mut self, request: EchoResponse, status: StatusCode) {
+        if self.capacity == 0 {
+            self.last_dropped = Some(Utc::now());
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.last_dropped = self.entries.pop_front().map(|entry| entry.timestamp);
+        }
+        self.entries.push_back(Entry {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+            status: status.as_u16(),
+            request
+        });
+    }
+
+    pub fn list(&self, query: &HistoryQuery) -> Vec<Entry> {
+        let matching: Vec<&Entry> = self.entries.iter().filter(|e| query.matches(e)).collect();
+        // With a limit, the most recent matching entries are returned.
+        let skip = query.limit.map(|limit| matching.len().saturating_sub(limit)).unwrap_or(0);
+        matching.into_iter().skip(skip).cloned().collect()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Entry> {
+        self.entries.iter().find(|e| e.id == id).cloned()
+    }
+
+    /// Whether requests received since the given moment, or ever, were
+    /// dropped to make room.
+    pub fn is_truncated(&self, since: Option<DateTime<Utc>>) -> bool {
+        self.last_dropped.is_some_and(|dropped| since.is_none_or(|since| dropped >= since))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.last_dropped = None;
+    }
+}
+
+async fn list_history(query: HistoryQuery) -> Result<impl Reply, Infallible> {
+    let entries = HISTORY.read().unwrap().list(&query);
+    Ok(warp::reply::json(&entries))
+}
+
+async fn get_entry(id: String) -> Result<impl Reply, Infallible> {
+    let reply = match HISTORY.read().unwrap().get(&id) {
+        Some(entry) => warp::reply::json(&entry).into_response(),
+        None => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "error": format!("No request with id {}", id)
+            })),
+            StatusCode::NOT_FOUND
+        ).into_response()
+    };
+    Ok(reply)
+}
+
+async fn clear_history() -> Result<impl Reply, Infallible> {
+    HISTORY.write().unwrap().clear();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn handle_invalid_query(err: Rejection) -> Result<impl Reply, Rejection> {
+    if let Some(invalid) = err.find::<warp::reject::InvalidQuery>() {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "error": invalid.to_string()
+            })),
+            StatusCode::BAD_REQUEST
+        ));
+    }
+    Err(err)
+}
+
+pub fn history_handler() -> BoxedFilter<(impl Reply,)> {
+    let list = warp::path::end()
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and_then(list_history)
+        .recover(handle_invalid_query);
+
+    let get = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_entry);
+
+    let clear = warp::path::end()
+        .and(warp::delete())
+        .and_then(clear_history);
+
+    list.or(get).or(clear).boxed()
+}
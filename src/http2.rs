@@ -0,0 +1,402 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use hyper::{HeaderMap, Method, Uri};
+use hyper_util::{rt::TokioExecutor, server::conn::auto};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::*;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LENGTH: usize = 9;
+// The largest frame a client has to accept before it says otherwise.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+const HEADERS: u8 = 0x1;
+const CONTINUATION: u8 = 0x9;
+const END_STREAM: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+
+lazy_static! {
+    static ref SETTINGS: Settings = Settings::from_env();
+}
+
+/// The HTTP/2 settings of the server, from the `HTTP2_*` environment variables.
+#[derive(Debug, Default)]
+struct Settings {
+    max_concurrent_streams: Option<u32>,
+    initial_stream_window_size: Option<u32>,
+    initial_connection_window_size: Option<u32>,
+    max_frame_size: Option<u32>,
+    max_header_list_size: Option<u32>
+}
+
+fn setting(variable: &str) -> Option<u32> {
+    let value = std::env::var(variable).ok().filter(|v| !v.is_empty())?;
+    match value.trim().parse() {
+        Ok(value) => Some(value),
+        Err(e) => panic!("{}: invalid value {}: {}", variable, value, e)
+    }
+}
+
+impl Settings {
+    fn from_env() -> Self {
+        Settings {
+            max_concurrent_streams: setting("HTTP2_MAX_CONCURRENT_STREAMS"),
+            initial_stream_window_size: setting("HTTP2_INITIAL_STREAM_WINDOW_SIZE"),
+            initial_connection_window_size: setting("HTTP2_INITIAL_CONNECTION_WINDOW_SIZE"),
+            max_frame_size: setting("HTTP2_MAX_FRAME_SIZE"),
+            max_header_list_size: setting("HTTP2_MAX_HEADER_LIST_SIZE")
+        }
+    }
+}
+
+/// Reads the HTTP/2 settings at startup so a broken value is reported straight away.
+pub fn init() {
+    lazy_static::initialize(&SETTINGS);
+    if let Some(size) = SETTINGS.max_frame_size.filter(|size| !(16_384..=16_777_215).contains(size)) {
+        panic!("HTTP2_MAX_FRAME_SIZE: {} is not between 16384 and 16777215", size);
+    }
+    debug!(settings = ?*SETTINGS, "HTTP/2 settings");
+}
+
+/// A connection builder that speaks HTTP/1 and HTTP/2 with the configured settings.
+pub fn builder() -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    let mut http2 = builder.http2();
+    http2.max_concurrent_streams(SETTINGS.max_concurrent_streams)
+        .initial_stream_window_size(SETTINGS.initial_stream_window_size)
+        .initial_connection_window_size(SETTINGS.initial_connection_window_size)
+        .max_frame_size(SETTINGS.max_frame_size);
+    if let Some(size) = SETTINGS.max_header_list_size {
+        http2.max_header_list_size(size);
+    }
+    builder
+}
+
+/// The header the stream a request arrived on is passed to the handlers in,
+/// see `StreamTagger`.
+pub const STREAM_ID_HEADER: &str = "echo-server-stream-id";
+
+enum TaggerState {
+    Preface(usize),
+    Header([u8; FRAME_HEADER_LENGTH], usize),
+    // The stream is set when the header block of a new stream ends with this frame.
+    Payload(usize, Option<u32>),
+    // Not an HTTP/2 connection.
+    Off
+}
+
+/// A stream that adds the id of the stream to the header block of every
+/// stream a client opens, as the `STREAM_ID_HEADER`, as hyper does not tell
+/// which stream a request came in on. The field is a literal that is not
+/// indexed, sent in a CONTINUATION frame of its own, so that neither the
+/// HPACK table nor the frames the client sent are disturbed.
+pub struct StreamTagger<S> {
+    inner: S,
+    state: TaggerState,
+    last_stream: u32,
+    // The new stream whose header block continues in the next frame.
+    open_block: Option<u32>,
+    out: Vec<u8>
+}
+
+impl<S> StreamTagger<S> {
+    pub fn new(inner: S) -> Self {
+        StreamTagger { inner, state: TaggerState::Preface(0), last_stream: 0, open_block: None, out: Vec::new() }
+    }
+
+    fn tag(&mut self, stream: u32) {
+        let mut block = vec![0x00];
+        encode_string(&mut block, STREAM_ID_HEADER.as_bytes());
+        encode_string(&mut block, stream.to_string().as_bytes());
+        let length = block.len() as u32;
+        self.out.extend_from_slice(&length.to_be_bytes()[1..]);
+        self.out.push(CONTINUATION);
+        self.out.push(END_HEADERS);
+        self.out.extend_from_slice(&stream.to_be_bytes());
+        self.out.extend_from_slice(&block);
+    }
+
+    fn process(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            match &mut self.state {
+                TaggerState::Off => {
+                    self.out.extend_from_slice(bytes);
+                    return;
+                },
+                TaggerState::Preface(seen) => {
+                    let take = bytes.len().min(PREFACE.len() - *seen);
+                    if bytes[..take] != PREFACE[*seen..*seen + take] {
+                        self.state = TaggerState::Off;
+                        continue;
+                    }
+                    *seen += take;
+                    if *seen == PREFACE.len() {
+                        self.state = TaggerState::Header([0; FRAME_HEADER_LENGTH], 0);
+                    }
+                    self.out.extend_from_slice(&bytes[..take]);
+                    bytes = &bytes[take..];
+                },
+                TaggerState::Header(header, have) => {
+                    let take = bytes.len().min(FRAME_HEADER_LENGTH - *have);
+                    header[*have..*have + take].copy_from_slice(&bytes[..take]);
+                    *have += take;
+                    bytes = &bytes[take..];
+                    if *have < FRAME_HEADER_LENGTH {
+                        continue;
+                    }
+                    let mut header = *header;
+                    let length = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+                    let stream = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+                    // Trailers come as HEADERS too, but on a stream that is already open.
+                    let opens = header[3] == HEADERS && stream > self.last_stream;
+                    if opens {
+                        self.last_stream = stream;
+                    }
+                    let mut tag = None;
+                    if opens || (header[3] == CONTINUATION && self.open_block == Some(stream)) {
+                        if header[4] & END_HEADERS != 0 {
+                            header[4] &= !END_HEADERS;
+                            self.open_block = None;
+                            tag = Some(stream);
+                        } else {
+                            self.open_block = Some(stream);
+                        }
+                    }
+                    self.out.extend_from_slice(&header);
+                    self.state = TaggerState::Payload(length, tag);
+                },
+                TaggerState::Payload(remaining, _) => {
+                    let take = bytes.len().min(*remaining);
+                    *remaining -= take;
+                    self.out.extend_from_slice(&bytes[..take]);
+                    bytes = &bytes[take..];
+                }
+            }
+            // A frame ends once its payload is through, which may be right after its header.
+            if let TaggerState::Payload(0, tag) = self.state {
+                if let Some(stream) = tag {
+                    self.tag(stream);
+                }
+                self.state = TaggerState::Header([0; FRAME_HEADER_LENGTH], 0);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for StreamTagger<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.out.is_empty() {
+                let take = this.out.len().min(buf.remaining());
+                buf.put_slice(&this.out[..take]);
+                this.out.drain(..take);
+                return Poll::Ready(Ok(()));
+            }
+            if let TaggerState::Off = this.state {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            let mut read = vec![0; buf.remaining().max(FRAME_HEADER_LENGTH)];
+            let mut read_buf = ReadBuf::new(&mut read);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) if read_buf.filled().is_empty() => {
+                    // Hand over what is left of a frame header cut short, if anything.
+                    if let TaggerState::Header(header, have) = this.state {
+                        this.out.extend_from_slice(&header[..have]);
+                        this.state = TaggerState::Off;
+                        continue;
+                    }
+                    return Poll::Ready(Ok(()));
+                },
+                Poll::Ready(Ok(())) => {
+                    let filled = read_buf.filled().to_vec();
+                    this.process(&filled);
+                },
+                other => return other
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for StreamTagger<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+/// Whether the request asks to upgrade its cleartext HTTP/1.1 connection to
+/// HTTP/2, as described in RFC 7540 section 3.2.
+pub fn is_h2c_upgrade(headers: &HeaderMap) -> bool {
+    let has_token = |name: &str, token: &str| headers.get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token));
+    has_token("upgrade", "h2c") && has_token("connection", "upgrade") && headers.contains_key("http2-settings")
+}
+
+// Headers that only mean something to the HTTP/1.1 connection.
+const CONNECTION_HEADERS: [&str; 8] = [
+    "connection", "upgrade", "http2-settings", "host", "keep-alive", "proxy-connection", "transfer-encoding", "te"
+];
+
+// An HPACK string literal, without Huffman coding.
+fn encode_string(out: &mut Vec<u8>, value: &[u8]) {
+    encode_integer(out, 0x00, 7, value.len());
+    out.extend_from_slice(value);
+}
+
+fn encode_integer(out: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 128 {
+        out.push((rest % 128) as u8 | 0x80);
+        rest /= 128;
+    }
+    out.push(rest as u8);
+}
+
+fn frame(out: &mut Vec<u8>, frame_type: u8, flags: u8, payload: &[u8]) {
+    let length = payload.len() as u32;
+    out.extend_from_slice(&length.to_be_bytes()[1..]);
+    out.push(frame_type);
+    out.push(flags);
+    out.extend_from_slice(&1u32.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// The frame a client would have sent for the request that upgraded the
+/// connection, on stream 1. Every header is a literal that is not indexed,
+/// so that the table the client encodes its own headers with stays in step.
+/// Returns `None` when the headers don't fit in a single frame.
+///
+/// Only requests without a body can be replayed: their data would arrive
+/// before the client has seen the server's settings, so anything past the
+/// default flow control window would break the connection.
+pub fn upgrade_request_frames(method: &Method, uri: &Uri, headers: &HeaderMap) -> Option<Bytes> {
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let authority = headers.get("host").map(|v| v.as_bytes()).unwrap_or_default();
+    let mut block = Vec::new();
+    let pseudo_headers: [(&[u8], &[u8]); 4] = [
+        (b":method", method.as_str().as_bytes()),
+        (b":scheme", b"http"),
+        (b":path", path.as_bytes()),
+        (b":authority", authority)
+    ];
+    let fields = headers.iter()
+        .filter(|(name, _)| !CONNECTION_HEADERS.contains(&name.as_str()))
+        .map(|(name, value)| (name.as_str().as_bytes(), value.as_bytes()));
+    for (name, value) in pseudo_headers.into_iter().chain(fields) {
+        // Literal header field without indexing, with a new name.
+        block.push(0x00);
+        encode_string(&mut block, name);
+        encode_string(&mut block, value);
+    }
+
+    // Larger header blocks would need CONTINUATION frames.
+    if block.len() > DEFAULT_MAX_FRAME_SIZE {
+        return None;
+    }
+    let mut frames = Vec::new();
+    frame(&mut frames, HEADERS, END_HEADERS | END_STREAM, &block);
+    Some(Bytes::from(frames))
+}
+
+/// An upgraded connection that slips the frames of the upgrade request in
+/// after the client's connection preface and first SETTINGS frame, so the
+/// HTTP/2 server answers it on stream 1 as the client expects.
+pub struct H2cUpgrade<S> {
+    inner: S,
+    seen: Vec<u8>,
+    injected: Option<Bytes>,
+    held: Bytes
+}
+
+impl<S> H2cUpgrade<S> {
+    pub fn new(inner: S, injected: Bytes) -> Self {
+        H2cUpgrade { inner, seen: Vec::new(), injected: Some(injected), held: Bytes::new() }
+    }
+
+    // Where the first frame after the preface ends, once that is known.
+    fn injection_point(&self) -> Option<usize> {
+        let header = self.seen.get(PREFACE.len()..PREFACE.len() + FRAME_HEADER_LENGTH)?;
+        let length = (header[0] as usize) << 16 | (header[1] as usize) << 8 | header[2] as usize;
+        Some(PREFACE.len() + FRAME_HEADER_LENGTH + length)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for H2cUpgrade<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.injected.is_none() {
+            if !this.held.is_empty() {
+                let take = this.held.len().min(buf.remaining());
+                buf.put_slice(&this.held.split_to(take));
+                return Poll::Ready(Ok(()));
+            }
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if this.injection_point().is_some_and(|point| this.seen.len() >= point) {
+            let injected = this.injected.as_mut().expect("checked above");
+            let take = injected.len().min(buf.remaining());
+            buf.put_slice(&injected.split_to(take));
+            if injected.is_empty() {
+                this.injected = None;
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = buf.filled()[before..].to_vec();
+            let offset = this.seen.len();
+            this.seen.extend_from_slice(&read);
+            // Hold back whatever the client sent after the point the request goes in.
+            if let Some(point) = this.injection_point().filter(|point| this.seen.len() > *point) {
+                let keep = point - offset;
+                this.held = Bytes::copy_from_slice(&read[keep..]);
+                this.seen.truncate(point);
+                buf.set_filled(before + keep);
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for H2cUpgrade<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
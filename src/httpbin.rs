@@ -0,0 +1,363 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use rand::{RngCore, SeedableRng, distr::{Distribution, weighted::WeightedIndex}, rngs::StdRng};
+use serde::Deserialize;
+use warp::{Filter, Reply, filters::BoxedFilter, host::Authority, http::HeaderValue, hyper::StatusCode, reply::Response};
+
+use crate::{api::IncomingRequest, control::MAX_DELAY, echo, history::HISTORY, server};
+
+// Upper bound for generated payloads, so a single request can't ask for gigabytes.
+const MAX_BYTES: usize = 10 * 1024 * 1024;
+const DEFAULT_CHUNK_SIZE: usize = 10 * 1024;
+// A drip is sent in at most this many pieces, however many bytes it has.
+const MAX_DRIPS: usize = 1000;
+
+fn error(status: StatusCode, message: String) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status
+    ).into_response()
+}
+
+fn octet_stream(mut response: Response) -> Response {
+    response.headers_mut().insert("content-type", HeaderValue::from_static("application/octet-stream"));
+    response
+}
+
+fn parse_query<'a, T: Deserialize<'a>>(query: &'a str) -> Result<T, String> {
+    serde_urlencoded::from_str(query).map_err(|e| format!("Invalid query: {}", e))
+}
+
+fn parse_seconds(value: f64, name: &str) -> Result<Duration, String> {
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("Invalid {} {}", name, value));
+    }
+    // Clamped first, as seconds past what a Duration holds would panic.
+    Ok(Duration::from_secs_f64(value.min(MAX_DELAY.as_secs_f64())))
+}
+
+// The generator for random payloads, repeatable when given a seed.
+fn rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_rng(&mut rand::rng())
+    }
+}
+
+// Parses a list of codes such as `200,201` or `200:0.9,500:0.1`. Codes
+// without a weight weigh 1.
+fn parse_codes(codes: &str) -> Result<Vec<(StatusCode, f64)>, String> {
+    codes.split(',').map(|item| {
+        let (code, weight) = match item.split_once(':') {
+            Some((code, weight)) => {
+                let weight = weight.trim().parse::<f64>().ok()
+                    .filter(|w| w.is_finite() && *w >= 0.0)
+                    .ok_or_else(|| format!("Invalid weight {}", weight))?;
+                (code, weight)
+            },
+            None => (item, 1.0)
+        };
+        let status = code.trim().parse::<u16>().ok()
+            .and_then(|c| StatusCode::from_u16(c).ok())
+            .filter(|s| !s.is_informational())
+            .ok_or_else(|| format!("Invalid status code {}", code))?;
+        Ok((status, weight))
+    }).collect()
+}
+
+/// Echoes the request with one of the status codes in the path, picked at
+/// random by weight when there are several.
+async fn status(codes: String, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let codes = match parse_codes(&codes) {
+        Ok(codes) => codes,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let status = match WeightedIndex::new(codes.iter().map(|(_, weight)| *weight)) {
+        Ok(index) => codes[index.sample(&mut rand::rng())].0,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, format!("Invalid weights: {}", e)))
+    };
+    let mut response = echo::controlled(request, status, &HISTORY).await;
+    // Give redirects somewhere to go, so clients that follow them end up back here.
+    if status.is_redirection() && status != StatusCode::NOT_MODIFIED {
+        response.headers_mut().insert("location", HeaderValue::from_static("/echo"));
+    }
+    Ok(response)
+}
+
+/// Echoes the request after waiting the number of seconds in the path.
+async fn delay(seconds: f64, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let delay = match parse_seconds(seconds, "delay") {
+        Ok(delay) => delay,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    tokio::time::sleep(delay).await;
+    Ok(echo::controlled(request, StatusCode::OK, &HISTORY).await)
+}
+
+/// How a drip is sent, from the `numbytes`, `duration`, `delay` and `code`
+/// query parameters. Times are in seconds.
+#[derive(Deserialize)]
+struct DripQuery {
+    numbytes: Option<usize>,
+    duration: Option<f64>,
+    delay: Option<f64>,
+    code: Option<u16>
+}
+
+/// Sends `numbytes` asterisks spread evenly over `duration`, after waiting
+/// for `delay`.
+async fn drip(request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let query: DripQuery = match parse_query(&request.query) {
+        Ok(query) => query,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let numbytes = query.numbytes.unwrap_or(10).min(MAX_BYTES);
+    let (duration, delay) = match (parse_seconds(query.duration.unwrap_or(2.0), "duration"), parse_seconds(query.delay.unwrap_or(0.0), "delay")) {
+        (Ok(duration), Ok(delay)) => (duration, delay),
+        (Err(message), _) | (_, Err(message)) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let status = match StatusCode::from_u16(query.code.unwrap_or(200)) {
+        Ok(status) if !status.is_informational() => status,
+        _ => return Ok(error(StatusCode::BAD_REQUEST, format!("Invalid status code {}", query.code.unwrap_or_default())))
+    };
+
+    tokio::time::sleep(delay).await;
+    let drips = numbytes.clamp(1, MAX_DRIPS);
+    let pause = duration / drips as u32;
+    let body = stream::iter(0..drips).then(move |i| async move {
+        if i > 0 {
+            tokio::time::sleep(pause).await;
+        }
+        // Spread the bytes so that the drips differ by at most one.
+        let size = numbytes * (i + 1) / drips - numbytes * i / drips;
+        Ok::<_, Infallible>(Bytes::from(vec![b'*'; size]))
+    });
+    let mut response = octet_stream(server::streaming(body));
+    *response.status_mut() = status;
+    response.headers_mut().insert("content-length", HeaderValue::from(numbytes));
+    Ok(response)
+}
+
+/// Makes random payloads repeatable with the `seed` query parameter.
+#[derive(Deserialize)]
+struct BytesQuery {
+    seed: Option<u64>,
+    chunk_size: Option<usize>
+}
+
+/// Returns `n` random bytes.
+async fn random_bytes(n: usize, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let query: BytesQuery = match parse_query(&request.query) {
+        Ok(query) => query,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let mut bytes = vec![0; n.min(MAX_BYTES)];
+    rng(query.seed).fill_bytes(&mut bytes);
+    Ok(octet_stream(Response::new(bytes.into())))
+}
+
+/// Streams `n` random bytes in chunks of `chunk_size`.
+async fn stream_bytes(n: usize, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let query: BytesQuery = match parse_query(&request.query) {
+        Ok(query) => query,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let chunk_size = query.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1);
+    let body = stream::unfold((n.min(MAX_BYTES), rng(query.seed)), move |(remaining, mut rng)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut chunk = vec![0; remaining.min(chunk_size)];
+        rng.fill_bytes(&mut chunk);
+        let rest = remaining - chunk.len();
+        Some((Ok::<_, Infallible>(Bytes::from(chunk)), (rest, rng)))
+    });
+    Ok(octet_stream(server::streaming(body)))
+}
+
+/// The status of a redirect, from the `status_code` query parameter.
+#[derive(Deserialize)]
+struct RedirectQuery {
+    status_code: Option<u16>,
+    // Only for `/redirect`, to make it redirect like `/absolute-redirect`.
+    absolute: Option<bool>
+}
+
+// The query the next hop of a chain is sent with, to keep its status.
+fn redirect_query(status: StatusCode) -> String {
+    if status == StatusCode::FOUND {
+        String::new()
+    } else {
+        format!("?status_code={}", status.as_u16())
+    }
+}
+
+fn redirect_status(code: Option<u16>) -> Result<StatusCode, String> {
+    let code = code.unwrap_or(302);
+    match code {
+        301 | 302 | 303 | 307 | 308 => Ok(StatusCode::from_u16(code).unwrap()),
+        _ => Err(format!("Invalid redirect status code {}, expected 301, 302, 303, 307 or 308", code))
+    }
+}
+
+fn redirect(status: StatusCode, location: &str) -> Response {
+    match HeaderValue::from_str(location) {
+        Ok(location) => {
+            let mut response = warp::reply::with_status(warp::reply(), status).into_response();
+            response.headers_mut().insert("location", location);
+            response
+        },
+        Err(_) => error(StatusCode::BAD_REQUEST, format!("Invalid redirect location {}", location))
+    }
+}
+
+// Where the hop after this one goes: the same kind of redirect with one
+// less to go, or the echo when this is the last.
+fn next_hop(kind: &str, n: u32, status: StatusCode) -> String {
+    match n {
+        0 | 1 => "/echo".to_string(),
+        n => format!("/{}/{}{}", kind, n - 1, redirect_query(status))
+    }
+}
+
+/// Redirects `n` times with relative locations before ending at the echo.
+async fn relative_redirect(n: u32, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let query: RedirectQuery = match parse_query(&request.query) {
+        Ok(query) => query,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let status = match redirect_status(query.status_code) {
+        Ok(status) => status,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    Ok(redirect(status, &next_hop("relative-redirect", n, status)))
+}
+
+/// Redirects `n` times with absolute locations before ending at the echo.
+async fn absolute_redirect(n: u32, authority: Option<Authority>, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let query: RedirectQuery = match parse_query(&request.query) {
+        Ok(query) => query,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let status = match redirect_status(query.status_code) {
+        Ok(status) => status,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let Some(authority) = authority else {
+        return Ok(error(StatusCode::BAD_REQUEST, "Absolute redirects need a Host header".to_string()));
+    };
+    let scheme = if request.tls.is_some() { "https" } else { "http" };
+    let location = format!("{}://{}{}", scheme, authority, next_hop("absolute-redirect", n, status));
+    Ok(redirect(status, &location))
+}
+
+/// Redirects `n` times, with relative locations unless `absolute` is set.
+async fn redirect_chain(n: u32, authority: Option<Authority>, request: IncomingRequest) -> Result<Response, Infallible> {
+    let query: RedirectQuery = match parse_query(&request.query) {
+        Ok(query) => query,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    if query.absolute.unwrap_or(false) {
+        absolute_redirect(n, authority, request).await.map(Reply::into_response)
+    } else {
+        relative_redirect(n, request).await.map(Reply::into_response)
+    }
+}
+
+/// The target of `/redirect-to`, from the `url` and `status_code` query parameters.
+#[derive(Deserialize)]
+struct RedirectToQuery {
+    url: Option<String>,
+    status_code: Option<u16>
+}
+
+/// Redirects to the given `url`.
+async fn redirect_to(request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let query: RedirectToQuery = match parse_query(&request.query) {
+        Ok(query) => query,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    let status = match redirect_status(query.status_code) {
+        Ok(status) => status,
+        Err(message) => return Ok(error(StatusCode::BAD_REQUEST, message))
+    };
+    match query.url.filter(|url| !url.is_empty()) {
+        Some(url) => Ok(redirect(status, &url)),
+        None => Ok(error(StatusCode::BAD_REQUEST, "Missing url to redirect to".to_string()))
+    }
+}
+
+pub fn status_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(status)
+        .boxed()
+}
+
+pub fn delay_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<f64>()
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(delay)
+        .boxed()
+}
+
+pub fn drip_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(echo::incoming_request())
+        .and_then(drip)
+        .boxed()
+}
+
+pub fn bytes_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<usize>()
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(random_bytes)
+        .boxed()
+}
+
+pub fn stream_bytes_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<usize>()
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(stream_bytes)
+        .boxed()
+}
+
+pub fn redirect_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<u32>()
+        .and(warp::path::end())
+        .and(warp::host::optional())
+        .and(echo::incoming_request())
+        .and_then(redirect_chain)
+        .boxed()
+}
+
+pub fn relative_redirect_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<u32>()
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(relative_redirect)
+        .boxed()
+}
+
+pub fn absolute_redirect_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<u32>()
+        .and(warp::path::end())
+        .and(warp::host::optional())
+        .and(echo::incoming_request())
+        .and_then(absolute_redirect)
+        .boxed()
+}
+
+pub fn redirect_to_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(echo::incoming_request())
+        .and_then(redirect_to)
+        .boxed()
+}
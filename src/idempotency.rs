@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use tracing::*;
+use warp::{Reply, http::{HeaderMap, HeaderValue, Method}, hyper::StatusCode, reply::Response};
+
+use crate::api::IncomingRequest;
+
+const DEFAULT_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_KEYS: usize = 10_000;
+
+lazy_static! {
+    /// How long keys are remembered, in seconds, set with the `IDEMPOTENCY_TTL` environment variable.
+    static ref TTL: Duration = Duration::from_secs(
+        std::env::var("IDEMPOTENCY_TTL")
+            .ok()
+            .and_then(|ttl| ttl.parse().ok())
+            .unwrap_or(DEFAULT_TTL_SECS)
+    );
+
+    /// How many keys are remembered at once, set with the `IDEMPOTENCY_MAX_KEYS` environment variable.
+    static ref MAX_KEYS: usize = std::env::var("IDEMPOTENCY_MAX_KEYS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_KEYS);
+
+    static ref KEYS: Mutex<HashMap<String, Entry>> = Mutex::new(HashMap::new());
+}
+
+struct Entry {
+    created: Instant,
+    fingerprint: Fingerprint,
+    // Empty while the first request with the key is still being handled.
+    response: Option<StoredResponse>
+}
+
+// What makes a repeated request the same request.
+#[derive(PartialEq)]
+struct Fingerprint {
+    method: Method,
+    path: String,
+    body: Bytes
+}
+
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes
+}
+
+impl StoredResponse {
+    fn replay(&self) -> Response {
+        let mut response = Response::new(self.body.clone().into());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response.headers_mut().insert("idempotent-replayed", HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// The `Idempotency-Key` of a request made with a method that is not
+/// idempotent by itself.
+pub fn key(request: &IncomingRequest) -> Option<String> {
+    if request.method != Method::POST && request.method != Method::PATCH {
+        return None;
+    }
+    request.headers.get("idempotency-key")
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+}
+
+// Forgets a key whose request was never answered, say because the client
+// went away, so that it can be retried.
+struct Pending(Option<String>);
+
+impl Pending {
+    fn complete(mut self, stored: StoredResponse) {
+        let Some(key) = self.0.take() else { return };
+        if let Some(entry) = KEYS.lock().unwrap().get_mut(&key) {
+            entry.response = Some(stored);
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(key) = self.0.take() {
+            KEYS.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status
+    ).into_response()
+}
+
+/// Answers a request carrying an idempotency key. The first request with the
+/// key is handled by `handle` and its response kept for the TTL, to be
+/// replayed for every repeat of the request. Reusing the key for a different
+/// request is refused with a 422, repeating a request that is still being
+/// handled with a 409, and a new key when too many are remembered with a 507.
+pub async fn respond<F>(key: String, request: IncomingRequest, handle: F) -> Response
+where
+    F: AsyncFnOnce(IncomingRequest) -> Response
+{
+    let fingerprint = Fingerprint {
+        method: request.method.clone(),
+        path: request.path.as_str().to_string(),
+        body: request.body.clone()
+    };
+    {
+        let mut keys = KEYS.lock().unwrap();
+        keys.retain(|_, entry| entry.created.elapsed() < *TTL);
+        if let Some(entry) = keys.get(&key) {
+            if entry.fingerprint != fingerprint {
+                return error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Idempotency key {} was used for a different request", key)
+                );
+            }
+            return match &entry.response {
+                Some(stored) => {
+                    debug!(key, "replaying idempotent response");
+                    stored.replay()
+                },
+                None => error(
+                    StatusCode::CONFLICT,
+                    format!("A request with idempotency key {} is still being processed", key)
+                )
+            };
+        }
+        if keys.len() >= *MAX_KEYS {
+            return error(
+                StatusCode::INSUFFICIENT_STORAGE,
+                format!("All {} idempotency keys are in use", *MAX_KEYS)
+            );
+        }
+        keys.insert(key.clone(), Entry { created: Instant::now(), fingerprint, response: None });
+    }
+
+    let pending = Pending(Some(key.clone()));
+    let (parts, body) = handle(request).await.into_parts();
+    let body = match body.collect().await {
+        Ok(collected) => collected.to_bytes(),
+        Err(e) => {
+            warn!(key, error = %e, "failed to read response to store");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read the response".to_string());
+        }
+    };
+    pending.complete(StoredResponse { status: parts.status, headers: parts.headers.clone(), body: body.clone() });
+    Response::from_parts(parts, body.into())
+}
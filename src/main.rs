@@ -4,11 +4,35 @@ extern crate lazy_static;
 extern crate prometheus;
 
 mod api;
+mod bins;
+mod body;
+mod chaos;
+mod compression;
+mod config;
+mod control;
+mod cookies;
 mod echo;
 mod ws;
 mod sse;
 mod metrics;
+mod mirror;
+mod mock;
+mod openapi;
 mod expensive;
+mod fingerprint;
+mod format;
+mod forwarded;
+mod history;
+mod http2;
+mod httpbin;
+mod idempotency;
+mod matcher;
+mod retry;
+mod scenario;
+mod server;
+mod templating;
+mod tls;
+mod verify;
 
 use std::net::SocketAddr;
 use std::str::FromStr;
@@ -47,6 +71,13 @@ async fn main() {
         .and_then(|string| SocketAddr::from_str(&string).ok())
         .unwrap_or_else(|| SocketAddr::from_str("127.0.0.1:9000").unwrap());
 
+    bins::init();
+    mock::init();
+    openapi::init();
+    scenario::init();
+    chaos::init();
+    http2::init();
+
     // Create the event bus and actor system
     let bus = EventBus::<ServerEvent>::new(1000);
     let system = ActorSystem::new("echo", bus);
@@ -72,6 +103,38 @@ async fn main() {
 
     let echo_route = warp::path("echo").and(echo::echo_handler());
 
+    let mirror_route = warp::path("mirror").and(mirror::mirror_handler());
+
+    let history_route = warp::path("_history").and(history::history_handler());
+
+    let bins_route = warp::path("bins").and(bins::bins_handler());
+
+    let admin_route = warp::path("_admin")
+        .and(warp::path("verify").and(verify::verify_handler())
+            .or(warp::path("scenarios").and(scenario::scenarios_handler()))
+            .or(warp::path("chaos").and(chaos::chaos_handler()))
+            .or(warp::path("retry").and(retry::counters_handler()))
+            .or(warp::path("bins").and(bins::admin_handler())));
+
+    let retry_route = warp::path("retry").and(retry::retry_handler());
+
+    let compressed_route = warp::path("gzip").and(compression::compressed_handler(compression::Encoding::Gzip))
+        .or(warp::path("deflate").and(compression::compressed_handler(compression::Encoding::Deflate)))
+        .or(warp::path("brotli").and(compression::compressed_handler(compression::Encoding::Brotli)))
+        .or(warp::path("zstd").and(compression::compressed_handler(compression::Encoding::Zstd)));
+
+    let httpbin_route = warp::path("status").and(httpbin::status_handler())
+        .or(warp::path("delay").and(httpbin::delay_handler()))
+        .or(warp::path("drip").and(httpbin::drip_handler()))
+        .or(warp::path("bytes").and(httpbin::bytes_handler()))
+        .or(warp::path("stream-bytes").and(httpbin::stream_bytes_handler()))
+        .or(warp::path("redirect").and(httpbin::redirect_handler()))
+        .or(warp::path("relative-redirect").and(httpbin::relative_redirect_handler()))
+        .or(warp::path("absolute-redirect").and(httpbin::absolute_redirect_handler()))
+        .or(warp::path("redirect-to").and(httpbin::redirect_to_handler()));
+
+    let cookies_route = warp::path("cookies").and(cookies::cookies_handler());
+
     let expensive_route = warp::path("expensive").and(expensive::expensive_handler());
 
     let favicon_route = warp::path("favicon.ico")
@@ -121,24 +184,42 @@ async fn main() {
     });
 
     // Create the warp routes
-    let routes = index_route
+    let routes = compression::wrap(chaos::wrap(index_route
         .or(favicon_route)       
         .or(expensive_route)        
         .or(echo_route)
+        .or(mirror_route)
+        .or(history_route)
+        .or(bins_route)
+        .or(retry_route)
+        .or(compressed_route)
+        .or(httpbin_route)
+        .or(cookies_route)
+        .or(admin_route)
         .or(teapot_route)
         .or(ws_route)
         .or(sse_route)      
         .or(metrics)  
-        .or(default_route)
-        
+        .or(default_route)))
         .with(cors)
         .with(log);
 
     // Start the server
     info!(%addr, "Echo server running");
     
+    let tls_server = async {
+        match tls::listener() {
+            Some((tls_addr, acceptor)) => {
+                info!(addr = %tls_addr, "Echo server running with TLS");
+                server::run_tls(routes.clone(), tls_addr, acceptor).await
+            },
+            None => std::future::pending().await
+        }
+    };
+
     tokio::select! {
-        _ = warp::serve(routes).run(addr) => {},
+        _ = server::run(routes.clone(), addr) => {},
+        _ = tls_server => {},
         _ = signal::ctrl_c() => {
             info!("Received Ctrl+C, shutting down gracefully...");
         }
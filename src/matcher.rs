@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+use crate::api::EchoResponse;
+
+/// A regular expression that is compiled when the configuration is loaded.
+#[derive(Clone, Debug)]
+pub struct Pattern(Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map(Pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Pattern {
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_str())
+    }
+}
+
+/// Matches a single value such as a header or query parameter. A plain string
+/// is shorthand for `equal_to`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StringMatcher {
+    EqualTo(String),
+    Rule(StringRule)
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StringRule {
+    equal_to: Option<String>,
+    contains: Option<String>,
+    matches: Option<Pattern>,
+    // When true, the value must not be present at all.
+    #[serde(default)]
+    absent: bool
+}
+
+impl StringMatcher {
+    fn rule(&self) -> StringRule {
+        match self {
+            StringMatcher::EqualTo(value) => StringRule {
+                equal_to: Some(value.clone()),
+                ..StringRule::default()
+            },
+            StringMatcher::Rule(rule) => rule.clone()
+        }
+    }
+
+    /// Whether any of the given values (all values of a repeated header, say) match.
+    fn matches_any(&self, values: &[&str]) -> bool {
+        let rule = self.rule();
+        if rule.absent {
+            return values.is_empty();
+        }
+        values.iter().any(|value| rule.matches(value))
+    }
+}
+
+impl StringRule {
+    fn matches(&self, value: &str) -> bool {
+        self.equal_to.as_ref().is_none_or(|expected| value == expected)
+            && self.contains.as_ref().is_none_or(|expected| value.contains(expected.as_str()))
+            && self.matches.as_ref().is_none_or(|pattern| pattern.0.is_match(value))
+    }
+}
+
+impl fmt::Display for StringMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rule = self.rule();
+        if rule.absent {
+            return write!(f, "absent");
+        }
+        let mut criteria = Vec::new();
+        if let Some(expected) = &rule.equal_to {
+            criteria.push(format!("equal to {:?}", expected));
+        }
+        if let Some(expected) = &rule.contains {
+            criteria.push(format!("containing {:?}", expected));
+        }
+        if let Some(pattern) = &rule.matches {
+            criteria.push(format!("matching /{}/", pattern));
+        }
+        if criteria.is_empty() {
+            criteria.push("present".to_string());
+        }
+        write!(f, "{}", criteria.join(" and "))
+    }
+}
+
+/// Matches the request body, either as a whole or the values selected by a JSON path.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyMatcher {
+    json_path: Option<JsonPath>,
+    equal_to_json: Option<Value>,
+    equal_to: Option<String>,
+    contains: Option<String>,
+    matches: Option<Pattern>
+}
+
+impl BodyMatcher {
+    fn rule(&self) -> StringRule {
+        StringRule {
+            equal_to: self.equal_to.clone(),
+            contains: self.contains.clone(),
+            matches: self.matches.clone(),
+            absent: false
+        }
+    }
+
+    fn matches(&self, body: &str) -> bool {
+        let rule = self.rule();
+        let Some(path) = &self.json_path else {
+            let json_matches = self.equal_to_json.as_ref().is_none_or(|expected| {
+                serde_json::from_str::<Value>(body).is_ok_and(|json| &json == expected)
+            });
+            return json_matches && rule.matches(body);
+        };
+        let Ok(json) = serde_json::from_str::<Value>(body) else {
+            return false;
+        };
+        path.query(&json).all().into_iter().any(|node| {
+            let text = match node {
+                Value::String(text) => text.clone(),
+                other => other.to_string()
+            };
+            self.equal_to_json.as_ref().is_none_or(|expected| node == expected)
+                && rule.matches(&text)
+        })
+    }
+}
+
+impl fmt::Display for BodyMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let target = match &self.json_path {
+            Some(path) => format!("body at {}", path),
+            None => "body".to_string()
+        };
+        let mut criteria = Vec::new();
+        if let Some(expected) = &self.equal_to_json {
+            criteria.push(format!("equal to JSON {}", expected));
+        }
+        if self.equal_to.is_some() || self.contains.is_some() || self.matches.is_some() {
+            criteria.push(StringMatcher::Rule(self.rule()).to_string());
+        }
+        if criteria.is_empty() {
+            criteria.push("present".to_string());
+        }
+        write!(f, "{} {}", target, criteria.join(" and "))
+    }
+}
+
+/// Criteria a request has to meet. Everything left out matches any request.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RequestMatcher {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub path_pattern: Option<Pattern>,
+    #[serde(default)]
+    pub query: BTreeMap<String, StringMatcher>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, StringMatcher>,
+    #[serde(default)]
+    pub body: Vec<BodyMatcher>
+}
+
+impl RequestMatcher {
+    pub fn matches(&self, request: &EchoResponse) -> bool {
+        self.mismatches(request).is_empty()
+    }
+
+    /// Describes every criterion the request fails to meet.
+    pub fn mismatches(&self, request: &EchoResponse) -> Vec<String> {
+        let mut mismatches = Vec::new();
+
+        if let Some(method) = &self.method {
+            if !method.eq_ignore_ascii_case("ANY") && !method.eq_ignore_ascii_case(request.method()) {
+                mismatches.push(format!("method: expected {}, was {}", method, request.method()));
+            }
+        }
+        if let Some(path) = &self.path {
+            if path != request.path() {
+                mismatches.push(format!("path: expected {}, was {}", path, request.path()));
+            }
+        }
+        if let Some(pattern) = &self.path_pattern {
+            if !pattern.0.is_match(request.path()) {
+                mismatches.push(format!("path: expected to match /{}/, was {}", pattern, request.path()));
+            }
+        }
+        for (name, matcher) in &self.query {
+            let values: Vec<&str> = request.query().get(name)
+                .map(|values| values.iter().map(String::as_str).collect())
+                .unwrap_or_default();
+            if !matcher.matches_any(&values) {
+                mismatches.push(format!("query {}: expected {}, was {:?}", name, matcher, values));
+            }
+        }
+        for (name, matcher) in &self.headers {
+            let values: Vec<&str> = request.headers().iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+                .collect();
+            if !matcher.matches_any(&values) {
+                mismatches.push(format!("header {}: expected {}, was {:?}", name, matcher, values));
+            }
+        }
+        let body = request.body_text().unwrap_or("");
+        for matcher in &self.body {
+            if !matcher.matches(body) {
+                mismatches.push(format!("{}, was {:?}", matcher, body));
+            }
+        }
+        mismatches
+    }
+}
@@ -1,5 +1,5 @@
 use warp::{Filter, Rejection, Reply, filters::BoxedFilter};
-use prometheus::{self, IntCounterVec};
+use prometheus::{self, IntCounterVec, IntGaugeVec};
 
 lazy_static! {
     pub static ref ECHO_COUNT: IntCounterVec = register_int_counter_vec!(
@@ -8,6 +8,27 @@ lazy_static! {
         &["method"]
     )
     .unwrap();
+
+    pub static ref CHAOS_FAULTS: IntCounterVec = register_int_counter_vec!(
+        "chaos_faults_total",
+        "faults injected by the chaos rules",
+        &["rule", "fault"]
+    )
+    .unwrap();
+
+    pub static ref CHAOS_WINDOW_ACTIVE: IntGaugeVec = register_int_gauge_vec!(
+        "chaos_window_active",
+        "whether the chaos window is open",
+        &["window"]
+    )
+    .unwrap();
+
+    pub static ref CHAOS_WINDOW_NEXT_START: IntGaugeVec = register_int_gauge_vec!(
+        "chaos_window_next_start_timestamp_seconds",
+        "when the chaos window opens next, in seconds since the Unix epoch",
+        &["window"]
+    )
+    .unwrap();
 }
 
 pub async fn collect_metrics() -> String {
@@ -0,0 +1,36 @@
+use bytes::Buf;
+use futures::{Stream, TryStreamExt};
+use warp::{Filter, Reply, filters::BoxedFilter, hyper::{HeaderMap, Method}, reply::Response};
+
+use crate::{metrics, server};
+
+// Request headers that describe the body and are returned with it.
+const MIRRORED_HEADERS: [&str; 3] = ["content-type", "content-encoding", "content-length"];
+
+/// Replies with exactly the bytes received. The request body is written back
+/// as it arrives instead of being buffered, so long uploads and chunked
+/// transfers stream straight through.
+pub fn mirror_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::method()
+        .and(warp::header::headers_cloned())
+        .and(warp::body::stream())
+        .map(mirror)
+        .boxed()
+}
+
+fn mirror<B>(method: Method, headers: HeaderMap, body: impl Stream<Item = Result<B, warp::Error>> + Send + 'static) -> Response
+where
+    B: Buf
+{
+    metrics::ECHO_COUNT
+        .with_label_values(&[method.as_str()])
+        .inc();
+    let body = body.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining()));
+    let mut response = server::streaming(body);
+    for name in MIRRORED_HEADERS {
+        for value in headers.get_all(name) {
+            response.headers_mut().append(name, value.clone());
+        }
+    }
+    response
+}
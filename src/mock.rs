@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::*;
+use warp::{http::{HeaderName, HeaderValue}, hyper::StatusCode, reply::Response};
+
+use crate::{api::{EchoResponse, IncomingRequest}, config, history::History, matcher::RequestMatcher};
+
+lazy_static! {
+    /// Stubs loaded from the file named by the `MOCK_RULES` environment variable.
+    static ref STUBS: Vec<Stub> = config::load_from_env::<MockConfig>("MOCK_RULES")
+        .map(|config| config.stubs)
+        .unwrap_or_default();
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MockConfig {
+    #[serde(default)]
+    stubs: Vec<Stub>
+}
+
+/// A canned response for the requests matching its criteria.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stub {
+    name: Option<String>,
+    #[serde(default)]
+    request: RequestMatcher,
+    response: MockResponse
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+/// A configured response, used by both the mock stubs and the scenarios.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockResponse {
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+    json_body: Option<Value>,
+    delay_ms: Option<u64>
+}
+
+impl MockResponse {
+    /// Reports a status that isn't a valid HTTP status code.
+    pub fn check(&self) -> Result<(), String> {
+        StatusCode::from_u16(self.status)
+            .map(|_| ())
+            .map_err(|_| format!("invalid status {}", self.status))
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Waits for the configured delay and builds the response.
+    pub async fn reply(&self) -> Response {
+        if let Some(delay) = self.delay_ms {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        let (body, content_type) = match (&self.json_body, &self.body) {
+            (Some(json), _) => (json.to_string(), Some("application/json")),
+            (None, Some(body)) => (body.clone(), None),
+            (None, None) => (String::new(), None)
+        };
+        let mut response = Response::new(body.into());
+        *response.status_mut() = self.status();
+        if let Some(content_type) = content_type {
+            response.headers_mut().insert("content-type", HeaderValue::from_static(content_type));
+        }
+        for (name, value) in &self.headers {
+            match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+                (Ok(name), Ok(value)) => {
+                    response.headers_mut().insert(name, value);
+                },
+                _ => warn!(name, value, "skipping invalid mock response header")
+            }
+        }
+        response
+    }
+}
+
+/// Loads the stubs at startup so a broken file is reported straight away.
+pub fn init() {
+    lazy_static::initialize(&STUBS);
+    for stub in STUBS.iter() {
+        if let Err(error) = stub.response.check() {
+            panic!("MOCK_RULES: {} in stub {}", error, stub.name.as_deref().unwrap_or("(unnamed)"));
+        }
+    }
+    if !STUBS.is_empty() {
+        info!(stubs = STUBS.len(), "Loaded mock stubs");
+    }
+}
+
+/// Answers the request with the first stub that matches it, recording the
+/// request in the history. Returns `None` when no stub matches.
+pub async fn respond(request: &IncomingRequest, history: &RwLock<History>) -> Option<Response> {
+    if STUBS.is_empty() {
+        return None;
+    }
+    let server = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
+    let echo = EchoResponse::new(request, server);
+    let stub = STUBS.iter().find(|stub| stub.request.matches(&echo))?;
+    debug!(stub = stub.name.as_deref().unwrap_or("(unnamed)"), path = echo.path(), "Matched mock stub");
+    let response = stub.response.reply().await;
+    history.write().unwrap().record(echo, response.status());
+    Some(response)
+}
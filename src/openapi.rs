@@ -0,0 +1,767 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use indexmap::IndexMap;
+use openapiv3::{
+    AdditionalProperties, Components, MediaType, OpenAPI, Operation, Parameter, ParameterSchemaOrContent,
+    PathItem, ReferenceOr, Schema, SchemaKind, StringFormat, StringType, Type, VariantOrUnknownOrEmpty
+};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use tracing::*;
+use warp::{http::HeaderValue, hyper::StatusCode, reply::Response};
+
+use crate::{api::{EchoResponse, IncomingRequest}, config, history::History};
+
+// How far references and nested schemas are followed, which keeps recursive
+// schemas from looping forever.
+const MAX_DEPTH: usize = 8;
+// Upper bound for the items and characters of a synthesized example, whatever
+// `minItems` or `minLength` the specification asks for.
+const MAX_EXAMPLE_SIZE: usize = 1000;
+// How many values and characters a synthesized example may hold in total, as
+// nested arrays would otherwise multiply their sizes.
+const MAX_EXAMPLE_BUDGET: usize = 10_000;
+
+lazy_static! {
+    /// The API described by the file named by the `OPENAPI_SPEC` environment variable.
+    static ref API: Option<Api> = config::load_from_env::<OpenAPI>("OPENAPI_SPEC").map(Api::new);
+}
+
+// Path parameter names and their decoded values.
+type PathParams = Vec<(String, String)>;
+
+struct Api {
+    spec: OpenAPI,
+    base_path: String,
+    routes: Vec<Route>,
+    // The `pattern`s of the string schemas, compiled when the specification is loaded.
+    patterns: HashMap<String, Regex>
+}
+
+/// A path template of the specification, compiled to a regular expression.
+struct Route {
+    template: String,
+    pattern: Regex,
+    params: Vec<String>,
+    literals: usize
+}
+
+impl Route {
+    fn new(template: &str) -> Self {
+        let mut pattern = String::from("^");
+        let mut params = Vec::new();
+        let mut literals = 0;
+        for segment in template.split('/').filter(|s| !s.is_empty()) {
+            pattern.push('/');
+            let mut rest = segment;
+            if !rest.contains('{') {
+                literals += 1;
+            }
+            while let Some(start) = rest.find('{') {
+                let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+                    break;
+                };
+                pattern.push_str(&regex::escape(&rest[..start]));
+                pattern.push_str("([^/]+)");
+                params.push(rest[start + 1..end].to_string());
+                rest = &rest[end + 1..];
+            }
+            pattern.push_str(&regex::escape(rest));
+        }
+        pattern.push_str("/?$");
+        Route {
+            template: template.to_string(),
+            pattern: Regex::new(&pattern).expect("escaped path template is a valid pattern"),
+            params,
+            literals
+        }
+    }
+}
+
+impl Api {
+    fn new(spec: OpenAPI) -> Self {
+        let base_path = spec.servers.first()
+            .map(|server| {
+                let mut url = server.url.clone();
+                for (name, variable) in server.variables.iter().flatten() {
+                    url = url.replace(&format!("{{{}}}", name), &variable.default);
+                }
+                let path = match url.split_once("://") {
+                    Some((_, rest)) => rest.find('/').map(|i| rest[i..].to_string()).unwrap_or_default(),
+                    None => url
+                };
+                path.trim_end_matches('/').to_string()
+            })
+            .unwrap_or_default();
+        let mut routes: Vec<Route> = spec.paths.paths.keys().map(|template| Route::new(template)).collect();
+        // Literal segments win over parameters, so /pets/mine is preferred over /pets/{id}.
+        routes.sort_by_key(|route| std::cmp::Reverse(route.literals));
+        let mut patterns = HashMap::new();
+        if let Ok(json) = serde_json::to_value(&spec) {
+            if let Some(multiple_of) = invalid_multiple_of(&json) {
+                panic!("OPENAPI_SPEC: multipleOf must be greater than 0, was {}", multiple_of);
+            }
+            collect_patterns(&json, &mut patterns);
+        }
+        Api { spec, base_path, routes, patterns }
+    }
+
+    fn operations(&self) -> usize {
+        self.spec.operations().count()
+    }
+
+    // Finds the path item for the request path, along with its path parameters.
+    fn find(&self, path: &str) -> Option<(&Route, &PathItem, PathParams)> {
+        let path = path.strip_prefix(self.base_path.as_str())?;
+        if !path.is_empty() && !path.starts_with('/') {
+            return None;
+        }
+        let path = if path.is_empty() { "/" } else { path };
+        self.routes.iter().find_map(|route| {
+            let captures = route.pattern.captures(path)?;
+            let item = self.spec.paths.paths.get(&route.template)?.as_item()?;
+            let params = route.params.iter()
+                .zip(captures.iter().skip(1))
+                .map(|(name, value)| {
+                    let value = value.map(|v| percent_decode_str(v.as_str()).decode_utf8_lossy().to_string());
+                    (name.clone(), value.unwrap_or_default())
+                })
+                .collect();
+            Some((route, item, params))
+        })
+    }
+
+    // Follows `$ref`s into the given section of the components.
+    fn resolve<'a, T>(
+        &'a self,
+        mut item: &'a ReferenceOr<T>,
+        section: impl Fn(&'a Components) -> &'a IndexMap<String, ReferenceOr<T>>
+    ) -> Option<&'a T> {
+        for _ in 0..MAX_DEPTH {
+            match item {
+                ReferenceOr::Item(value) => return Some(value),
+                ReferenceOr::Reference { reference } => {
+                    let name = reference.strip_prefix("#/components/")?.split_once('/')?.1;
+                    item = section(self.spec.components.as_ref()?).get(name)?;
+                }
+            }
+        }
+        None
+    }
+
+    fn schema<'a>(&'a self, schema: &'a ReferenceOr<Schema>) -> Option<&'a Schema> {
+        self.resolve(schema, |c| &c.schemas)
+    }
+
+    fn boxed_schema<'a>(&'a self, schema: &'a ReferenceOr<Box<Schema>>) -> Option<&'a Schema> {
+        match schema {
+            ReferenceOr::Item(schema) => Some(schema),
+            ReferenceOr::Reference { reference } => {
+                let name = reference.strip_prefix("#/components/schemas/")?;
+                let schema = self.spec.components.as_ref()?.schemas.get(name)?;
+                self.schema(schema)
+            }
+        }
+    }
+
+    // The parameters of the operation, including those declared on the path
+    // item that the operation doesn't override.
+    fn parameters<'a>(&'a self, item: &'a PathItem, operation: &'a Operation) -> Vec<&'a Parameter> {
+        let operation_parameters: Vec<&Parameter> = operation.parameters.iter()
+            .filter_map(|p| self.resolve(p, |c| &c.parameters))
+            .collect();
+        let mut parameters: Vec<&Parameter> = item.parameters.iter()
+            .filter_map(|p| self.resolve(p, |c| &c.parameters))
+            .filter(|p| !operation_parameters.iter().any(|o| same_parameter(o, p)))
+            .collect();
+        parameters.extend(operation_parameters);
+        parameters
+    }
+
+    /// Checks the request against the parameters and request body the operation
+    /// declares, describing every violation.
+    fn validate_request(&self, request: &IncomingRequest, echo: &EchoResponse, item: &PathItem, operation: &Operation, path_params: &[(String, String)]) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for parameter in self.parameters(item, operation) {
+            let data = parameter.parameter_data_ref();
+            let (location, values): (&str, Vec<String>) = match parameter {
+                Parameter::Path { .. } => ("path", path_params.iter()
+                    .filter(|(name, _)| name == &data.name)
+                    .map(|(_, value)| value.clone())
+                    .collect()),
+                Parameter::Query { .. } => ("query", echo.query().get(&data.name).cloned().unwrap_or_default()),
+                Parameter::Header { .. } => ("header", request.headers.get_all(data.name.as_str()).iter()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+                    .collect()),
+                Parameter::Cookie { .. } => ("cookie", cookies(request)
+                    .filter(|(name, _)| name == &data.name)
+                    .map(|(_, value)| value)
+                    .collect())
+            };
+            let at = format!("{} parameter {}", location, data.name);
+            if values.is_empty() {
+                if data.required {
+                    errors.push(format!("{}: required but missing", at));
+                }
+                continue;
+            }
+            let ParameterSchemaOrContent::Schema(schema) = &data.format else {
+                continue;
+            };
+            let Some(schema) = self.schema(schema) else {
+                continue;
+            };
+            if let Some(value) = self.coerce(&values, schema) {
+                self.validate(&value, schema, &at, &mut errors, 0);
+            }
+        }
+
+        let Some(body) = &operation.request_body else {
+            return errors;
+        };
+        let Some(body) = self.resolve(body, |c| &c.request_bodies) else {
+            return errors;
+        };
+        if request.body.is_empty() {
+            if body.required {
+                errors.push("body: required but missing".to_string());
+            }
+            return errors;
+        }
+        let content_type = request.headers.get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<mime::Mime>().ok());
+        let Some(content_type) = content_type else {
+            errors.push("body: the request has no valid content-type".to_string());
+            return errors;
+        };
+        let Some(media) = find_media_type(&body.content, &content_type) else {
+            let accepted: Vec<&str> = body.content.keys().map(String::as_str).collect();
+            errors.push(format!("body: content type {} is not one of {:?}", content_type.essence_str(), accepted));
+            return errors;
+        };
+        let is_json = content_type.subtype() == mime::JSON || content_type.suffix() == Some(mime::JSON);
+        if let (true, Some(schema)) = (is_json, media.schema.as_ref().and_then(|s| self.schema(s))) {
+            match serde_json::from_slice::<Value>(&request.body) {
+                Ok(json) => self.validate(&json, schema, "body", &mut errors, 0),
+                Err(error) => errors.push(format!("body: invalid JSON: {}", error))
+            }
+        }
+        errors
+    }
+
+    // Turns the raw parameter values into the JSON type the schema asks for.
+    // Values that don't parse are left as strings, for validation to report.
+    fn coerce(&self, values: &[String], schema: &Schema) -> Option<Value> {
+        match &schema.schema_kind {
+            SchemaKind::Type(Type::Array(array)) => {
+                let items = array.items.as_ref().and_then(|items| self.boxed_schema(items));
+                let values: Vec<&str> = match values {
+                    [single] => single.split(',').collect(),
+                    values => values.iter().map(String::as_str).collect()
+                };
+                Some(Value::Array(values.into_iter()
+                    .map(|value| items.map(|items| coerce_scalar(value, items)).unwrap_or_else(|| json!(value)))
+                    .collect()))
+            },
+            SchemaKind::Type(Type::Object(_)) => None,
+            _ => values.last().map(|value| coerce_scalar(value, schema))
+        }
+    }
+
+    fn validate(&self, value: &Value, schema: &Schema, at: &str, errors: &mut Vec<String>, depth: usize) {
+        if depth > MAX_DEPTH || (value.is_null() && schema.schema_data.nullable) {
+            return;
+        }
+        match &schema.schema_kind {
+            SchemaKind::Type(Type::String(string)) => {
+                let Some(text) = value.as_str() else {
+                    return errors.push(format!("{}: expected a string, was {}", at, value));
+                };
+                if !string.enumeration.is_empty() && !string.enumeration.iter().any(|e| e.as_deref() == Some(text)) {
+                    errors.push(format!("{}: expected one of {:?}, was {:?}", at, string.enumeration.iter().flatten().collect::<Vec<_>>(), text));
+                }
+                let length = text.chars().count();
+                if string.min_length.is_some_and(|min| length < min) || string.max_length.is_some_and(|max| length > max) {
+                    errors.push(format!("{}: length {} is outside {}..{}", at, length,
+                        string.min_length.unwrap_or(0),
+                        string.max_length.map(|max| max.to_string()).unwrap_or_default()));
+                }
+                if let Some(pattern) = string.pattern.as_ref().and_then(|p| self.patterns.get(p)) {
+                    if !pattern.is_match(text) {
+                        errors.push(format!("{}: expected to match /{}/, was {:?}", at, pattern, text));
+                    }
+                }
+                if let Some(format) = invalid_format(&string.format, text) {
+                    errors.push(format!("{}: expected a {}, was {:?}", at, format, text));
+                }
+            },
+            SchemaKind::Type(Type::Integer(integer)) => {
+                let Some(number) = value.as_i64() else {
+                    return errors.push(format!("{}: expected an integer, was {}", at, value));
+                };
+                if !integer.enumeration.is_empty() && !integer.enumeration.contains(&Some(number)) {
+                    errors.push(format!("{}: expected one of {:?}, was {}", at, integer.enumeration.iter().flatten().collect::<Vec<_>>(), number));
+                }
+                check_range(number as f64, integer.minimum.map(|m| m as f64), integer.exclusive_minimum,
+                    integer.maximum.map(|m| m as f64), integer.exclusive_maximum, at, errors);
+                if integer.multiple_of.is_some_and(|m| number.checked_rem(m).is_some_and(|rest| rest != 0)) {
+                    errors.push(format!("{}: expected a multiple of {}, was {}", at, integer.multiple_of.unwrap_or(1), number));
+                }
+            },
+            SchemaKind::Type(Type::Number(float)) => {
+                let Some(number) = value.as_f64() else {
+                    return errors.push(format!("{}: expected a number, was {}", at, value));
+                };
+                if !float.enumeration.is_empty() && !float.enumeration.contains(&Some(number)) {
+                    errors.push(format!("{}: expected one of {:?}, was {}", at, float.enumeration.iter().flatten().collect::<Vec<_>>(), number));
+                }
+                check_range(number, float.minimum, float.exclusive_minimum, float.maximum, float.exclusive_maximum, at, errors);
+            },
+            SchemaKind::Type(Type::Boolean(boolean)) => {
+                let Some(flag) = value.as_bool() else {
+                    return errors.push(format!("{}: expected a boolean, was {}", at, value));
+                };
+                if !boolean.enumeration.is_empty() && !boolean.enumeration.contains(&Some(flag)) {
+                    errors.push(format!("{}: expected one of {:?}, was {}", at, boolean.enumeration.iter().flatten().collect::<Vec<_>>(), flag));
+                }
+            },
+            SchemaKind::Type(Type::Object(object)) => {
+                self.validate_object(value, &object.properties, &object.required, object.additional_properties.as_ref(), at, errors, depth);
+            },
+            SchemaKind::Type(Type::Array(array)) => {
+                let Some(items) = value.as_array() else {
+                    return errors.push(format!("{}: expected an array, was {}", at, value));
+                };
+                if array.min_items.is_some_and(|min| items.len() < min) || array.max_items.is_some_and(|max| items.len() > max) {
+                    errors.push(format!("{}: {} items is outside {}..{}", at, items.len(),
+                        array.min_items.unwrap_or(0),
+                        array.max_items.map(|max| max.to_string()).unwrap_or_default()));
+                }
+                if let Some(schema) = array.items.as_ref().and_then(|items| self.boxed_schema(items)) {
+                    for (i, item) in items.iter().enumerate() {
+                        self.validate(item, schema, &format!("{}[{}]", at, i), errors, depth + 1);
+                    }
+                }
+            },
+            SchemaKind::OneOf { one_of } => {
+                let matching = one_of.iter().filter(|s| self.is_valid(value, s, depth)).count();
+                if matching != 1 {
+                    errors.push(format!("{}: expected exactly one of the oneOf schemas to match, {} did", at, matching));
+                }
+            },
+            SchemaKind::AnyOf { any_of } => {
+                if !any_of.iter().any(|s| self.is_valid(value, s, depth)) {
+                    errors.push(format!("{}: expected at least one of the anyOf schemas to match", at));
+                }
+            },
+            SchemaKind::AllOf { all_of } => {
+                for schema in all_of.iter().filter_map(|s| self.schema(s)) {
+                    self.validate(value, schema, at, errors, depth + 1);
+                }
+            },
+            SchemaKind::Not { not } => {
+                if self.is_valid(value, not, depth) {
+                    errors.push(format!("{}: expected not to match the schema", at));
+                }
+            },
+            SchemaKind::Any(any) => {
+                if !any.properties.is_empty() || !any.required.is_empty() {
+                    self.validate_object(value, &any.properties, &any.required, any.additional_properties.as_ref(), at, errors, depth);
+                }
+                if !any.enumeration.is_empty() && !any.enumeration.contains(value) {
+                    errors.push(format!("{}: expected one of {:?}, was {}", at, any.enumeration, value));
+                }
+                for schema in any.all_of.iter().filter_map(|s| self.schema(s)) {
+                    self.validate(value, schema, at, errors, depth + 1);
+                }
+            }
+        }
+    }
+
+    fn is_valid(&self, value: &Value, schema: &ReferenceOr<Schema>, depth: usize) -> bool {
+        let mut errors = Vec::new();
+        if let Some(schema) = self.schema(schema) {
+            self.validate(value, schema, "", &mut errors, depth + 1);
+        }
+        errors.is_empty()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn validate_object(
+        &self,
+        value: &Value,
+        properties: &IndexMap<String, ReferenceOr<Box<Schema>>>,
+        required: &[String],
+        additional: Option<&AdditionalProperties>,
+        at: &str,
+        errors: &mut Vec<String>,
+        depth: usize
+    ) {
+        let Some(object) = value.as_object() else {
+            return errors.push(format!("{}: expected an object, was {}", at, value));
+        };
+        for name in required.iter().filter(|name| !object.contains_key(name.as_str())) {
+            errors.push(format!("{}.{}: required but missing", at, name));
+        }
+        for (name, value) in object {
+            let at = format!("{}.{}", at, name);
+            match (properties.get(name), additional) {
+                (Some(schema), _) => {
+                    if let Some(schema) = self.boxed_schema(schema) {
+                        self.validate(value, schema, &at, errors, depth + 1);
+                    }
+                },
+                (None, Some(AdditionalProperties::Any(false))) => {
+                    errors.push(format!("{}: not an allowed property", at));
+                },
+                (None, Some(AdditionalProperties::Schema(schema))) => {
+                    if let Some(schema) = self.schema(schema) {
+                        self.validate(value, schema, &at, errors, depth + 1);
+                    }
+                },
+                (None, _) => {}
+            }
+        }
+    }
+
+    /// Builds an example value for the schema, preferring the examples and
+    /// defaults the specification gives. Every value and character built
+    /// takes from the budget, and once it runs out the rest is left empty.
+    fn example(&self, schema: &Schema, depth: usize, budget: &mut usize) -> Value {
+        if let Some(example) = schema.schema_data.example.as_ref().or(schema.schema_data.default.as_ref()) {
+            return example.clone();
+        }
+        if depth > MAX_DEPTH || *budget == 0 {
+            return Value::Null;
+        }
+        *budget -= 1;
+        match &schema.schema_kind {
+            SchemaKind::Type(Type::String(string)) => string.enumeration.iter().flatten().next()
+                .map(|value| json!(value))
+                .unwrap_or_else(|| json!(example_string(string, budget))),
+            SchemaKind::Type(Type::Integer(integer)) => {
+                let number = integer.enumeration.iter().flatten().next().copied()
+                    .or(integer.minimum.and_then(|min| if integer.exclusive_minimum { min.checked_add(1) } else { Some(min) }))
+                    .or(integer.maximum.and_then(|max| if integer.exclusive_maximum { max.checked_sub(1) } else { Some(max) }).filter(|max| *max < 0))
+                    .unwrap_or(0);
+                json!(number)
+            },
+            SchemaKind::Type(Type::Number(float)) => {
+                let number = float.enumeration.iter().flatten().next().copied()
+                    .or(float.minimum)
+                    .or(float.maximum.filter(|max| *max < 0.0))
+                    .unwrap_or(0.0);
+                json!(number)
+            },
+            SchemaKind::Type(Type::Boolean(boolean)) => json!(boolean.enumeration.iter().flatten().next().copied().unwrap_or(true)),
+            SchemaKind::Type(Type::Object(object)) => self.example_object(&object.properties, depth, budget),
+            SchemaKind::Type(Type::Array(array)) => {
+                let count = array.min_items.unwrap_or(1).clamp(1, MAX_EXAMPLE_SIZE).min(array.max_items.unwrap_or(usize::MAX));
+                let items = array.items.as_ref().and_then(|items| self.boxed_schema(items));
+                let mut values = Vec::new();
+                while values.len() < count && *budget > 0 {
+                    values.push(items.map(|items| self.example(items, depth + 1, budget)).unwrap_or(Value::Null));
+                }
+                Value::Array(values)
+            },
+            SchemaKind::OneOf { one_of: schemas } | SchemaKind::AnyOf { any_of: schemas } => schemas.iter()
+                .find_map(|s| self.schema(s))
+                .map(|s| self.example(s, depth + 1, budget))
+                .unwrap_or(Value::Null),
+            SchemaKind::AllOf { all_of } => {
+                let mut merged = Map::new();
+                for schema in all_of.iter().filter_map(|s| self.schema(s)) {
+                    match self.example(schema, depth + 1, budget) {
+                        Value::Object(object) => merged.extend(object),
+                        other => return other
+                    }
+                }
+                Value::Object(merged)
+            },
+            SchemaKind::Not { .. } => Value::Null,
+            SchemaKind::Any(any) => {
+                if let Some(value) = any.enumeration.first() {
+                    value.clone()
+                } else if !any.properties.is_empty() || any.typ.as_deref() == Some("object") {
+                    self.example_object(&any.properties, depth, budget)
+                } else {
+                    match any.typ.as_deref() {
+                        Some("string") => json!("string"),
+                        Some("integer") | Some("number") => json!(0),
+                        Some("boolean") => json!(true),
+                        Some("array") => json!([]),
+                        _ => Value::Null
+                    }
+                }
+            }
+        }
+    }
+
+    fn example_object(&self, properties: &IndexMap<String, ReferenceOr<Box<Schema>>>, depth: usize, budget: &mut usize) -> Value {
+        let object = properties.iter()
+            .filter_map(|(name, schema)| {
+                let schema = self.boxed_schema(schema)?;
+                Some((name.clone(), self.example(schema, depth + 1, budget)))
+            })
+            .collect();
+        Value::Object(object)
+    }
+
+    /// Picks the response to mock: the status asked for with `Prefer: code=`,
+    /// or else the first success response, or else the default one.
+    fn mock_response(&self, operation: &Operation, request: &IncomingRequest) -> Response {
+        let prefer = preferences(request);
+        let preferred_code = prefer.iter()
+            .find(|(name, _)| name == "code")
+            .and_then(|(_, code)| code.parse::<u16>().ok());
+        let responses = &operation.responses.responses;
+        let chosen = preferred_code
+            .and_then(|code| responses.get_key_value(&openapiv3::StatusCode::Code(code)))
+            .or_else(|| responses.iter().find(|(code, _)| match code {
+                openapiv3::StatusCode::Code(code) => (200..300).contains(code),
+                openapiv3::StatusCode::Range(range) => *range == 2
+            }))
+            .map(|(code, response)| (code.clone(), response))
+            .or_else(|| operation.responses.default.as_ref()
+                .map(|response| (openapiv3::StatusCode::Code(preferred_code.unwrap_or(200)), response)));
+        let Some((code, response)) = chosen else {
+            return Response::new(Vec::new().into());
+        };
+        let status = match code {
+            openapiv3::StatusCode::Code(code) => StatusCode::from_u16(code).unwrap_or(StatusCode::OK),
+            openapiv3::StatusCode::Range(range) => StatusCode::from_u16(range * 100).unwrap_or(StatusCode::OK)
+        };
+        let mut reply = Response::new(Vec::new().into());
+        *reply.status_mut() = status;
+        let Some(response) = self.resolve(response, |c| &c.responses) else {
+            return reply;
+        };
+        let accept = request.headers.get("accept").and_then(|v| v.to_str().ok()).unwrap_or("");
+        let media = response.content.iter()
+            .find(|(media_type, _)| accept.contains(media_type.as_str()))
+            .or_else(|| response.content.first());
+        let Some((media_type, media)) = media else {
+            return reply;
+        };
+        let named = prefer.iter().find(|(name, _)| name == "example").map(|(_, name)| name.as_str());
+        let value = self.media_example(media, named);
+        let body = match value {
+            Value::String(text) if !media_type.contains("json") => text.into_bytes(),
+            value => serde_json::to_vec(&value).unwrap_or_default()
+        };
+        let content_type = if media_type.contains('*') { "application/json" } else { media_type.as_str() };
+        if let Ok(content_type) = HeaderValue::from_str(content_type) {
+            reply.headers_mut().insert("content-type", content_type);
+        }
+        *reply.body_mut() = body.into();
+        reply
+    }
+
+    fn media_example(&self, media: &MediaType, named: Option<&str>) -> Value {
+        let example = |example: &ReferenceOr<openapiv3::Example>| {
+            self.resolve(example, |c| &c.examples).and_then(|e| e.value.clone())
+        };
+        named.and_then(|name| media.examples.get(name)).and_then(example)
+            .or_else(|| media.example.clone())
+            .or_else(|| media.examples.values().find_map(example))
+            .or_else(|| media.schema.as_ref().and_then(|s| self.schema(s)).map(|s| self.example(s, 0, &mut { MAX_EXAMPLE_BUDGET })))
+            .unwrap_or(Value::Null)
+    }
+}
+
+fn same_parameter(a: &Parameter, b: &Parameter) -> bool {
+    std::mem::discriminant(a) == std::mem::discriminant(b)
+        && a.parameter_data_ref().name == b.parameter_data_ref().name
+}
+
+fn cookies(request: &IncomingRequest) -> impl Iterator<Item = (String, String)> + '_ {
+    request.headers.get_all("cookie").iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+}
+
+// The `name=value` preferences of the `Prefer` header.
+fn preferences(request: &IncomingRequest) -> Vec<(String, String)> {
+    request.headers.get_all("prefer").iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split([',', ';']))
+        .filter_map(|preference| preference.split_once('='))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().trim_matches('"').to_string()))
+        .collect()
+}
+
+fn find_media_type<'a>(content: &'a IndexMap<String, MediaType>, content_type: &mime::Mime) -> Option<&'a MediaType> {
+    content.iter()
+        .find(|(media_type, _)| media_type.parse::<mime::Mime>().is_ok_and(|m| m.essence_str() == content_type.essence_str()))
+        .or_else(|| content.iter().find(|(media_type, _)| {
+            *media_type == "*/*" || media_type.strip_suffix("/*").is_some_and(|t| t == content_type.type_().as_str())
+        }))
+        .map(|(_, media)| media)
+}
+
+fn coerce_scalar(value: &str, schema: &Schema) -> Value {
+    let parsed = match &schema.schema_kind {
+        SchemaKind::Type(Type::Integer(_)) => value.parse::<i64>().ok().map(Value::from),
+        SchemaKind::Type(Type::Number(_)) => value.parse::<f64>().ok().map(Value::from),
+        SchemaKind::Type(Type::Boolean(_)) => value.parse::<bool>().ok().map(Value::from),
+        _ => None
+    };
+    parsed.unwrap_or_else(|| json!(value))
+}
+
+fn check_range(number: f64, minimum: Option<f64>, exclusive_minimum: bool, maximum: Option<f64>, exclusive_maximum: bool, at: &str, errors: &mut Vec<String>) {
+    if let Some(min) = minimum {
+        if number < min || (exclusive_minimum && number == min) {
+            errors.push(format!("{}: expected at least {}{}, was {}", at, min, if exclusive_minimum { " (exclusive)" } else { "" }, number));
+        }
+    }
+    if let Some(max) = maximum {
+        if number > max || (exclusive_maximum && number == max) {
+            errors.push(format!("{}: expected at most {}{}, was {}", at, max, if exclusive_maximum { " (exclusive)" } else { "" }, number));
+        }
+    }
+}
+
+// Names the format the text fails to meet, for the formats that are checked.
+fn invalid_format(format: &VariantOrUnknownOrEmpty<StringFormat>, text: &str) -> Option<&'static str> {
+    match format {
+        VariantOrUnknownOrEmpty::Item(StringFormat::Date) => {
+            chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").is_err().then_some("date")
+        },
+        VariantOrUnknownOrEmpty::Item(StringFormat::DateTime) => {
+            chrono::DateTime::parse_from_rfc3339(text).is_err().then_some("date-time")
+        },
+        VariantOrUnknownOrEmpty::Unknown(format) if format == "uuid" => {
+            uuid::Uuid::parse_str(text).is_err().then_some("uuid")
+        },
+        _ => None
+    }
+}
+
+// Compiles every string `pattern` found in the specification. Patterns that
+// don't compile are reported once here, and skipped when validating.
+fn collect_patterns(value: &Value, patterns: &mut HashMap<String, Regex>) {
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::String(pattern)) = object.get("pattern") {
+                if !patterns.contains_key(pattern) {
+                    match Regex::new(pattern) {
+                        Ok(regex) => { patterns.insert(pattern.clone(), regex); },
+                        Err(error) => warn!(pattern, %error, "ignoring invalid OpenAPI pattern")
+                    }
+                }
+            }
+            object.values().for_each(|value| collect_patterns(value, patterns));
+        },
+        Value::Array(values) => values.iter().for_each(|value| collect_patterns(value, patterns)),
+        _ => {}
+    }
+}
+
+// The first `multipleOf` in the specification that is not above zero, which
+// no value could ever be a multiple of.
+fn invalid_multiple_of(value: &Value) -> Option<f64> {
+    match value {
+        Value::Object(object) => object.get("multipleOf")
+            .and_then(Value::as_f64)
+            .filter(|multiple_of| *multiple_of <= 0.0)
+            .or_else(|| object.values().find_map(invalid_multiple_of)),
+        Value::Array(values) => values.iter().find_map(invalid_multiple_of),
+        _ => None
+    }
+}
+
+fn example_string(string: &StringType, budget: &mut usize) -> String {
+    let example = match &string.format {
+        VariantOrUnknownOrEmpty::Item(StringFormat::Date) => "2024-01-01",
+        VariantOrUnknownOrEmpty::Item(StringFormat::DateTime) => "2024-01-01T00:00:00Z",
+        VariantOrUnknownOrEmpty::Item(StringFormat::Password) => "********",
+        VariantOrUnknownOrEmpty::Item(StringFormat::Byte) => "c3RyaW5n",
+        VariantOrUnknownOrEmpty::Item(StringFormat::Binary) => "",
+        VariantOrUnknownOrEmpty::Unknown(format) => match format.as_str() {
+            "uuid" => "3fa85f64-5717-4562-b3fc-2c963f66afa6",
+            "email" => "user@example.com",
+            "uri" | "url" => "https://example.com",
+            "hostname" => "example.com",
+            "ipv4" => "192.0.2.1",
+            "ipv6" => "2001:db8::1",
+            _ => "string"
+        },
+        VariantOrUnknownOrEmpty::Empty => "string"
+    };
+    let mut example = example.to_string();
+    let min = string.min_length.unwrap_or(0).min(MAX_EXAMPLE_SIZE);
+    let padding = min.saturating_sub(example.chars().count()).min(*budget);
+    example.extend(std::iter::repeat_n('x', padding));
+    *budget -= padding;
+    if let Some(max) = string.max_length {
+        example = example.chars().take(max).collect();
+    }
+    example
+}
+
+fn error_response(status: StatusCode, body: Value) -> Response {
+    let mut response = Response::new(body.to_string().into());
+    *response.status_mut() = status;
+    response.headers_mut().insert("content-type", HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Loads the specification at startup so a broken file is reported straight away.
+pub fn init() {
+    lazy_static::initialize(&API);
+    if let Some(api) = API.as_ref() {
+        info!(operations = api.operations(), base_path = api.base_path, "Loaded OpenAPI specification");
+    }
+}
+
+/// Answers requests for the paths the specification declares: a 405 when the
+/// path lacks the method, a 400 listing what is wrong with an invalid request,
+/// and otherwise the example response. Returns `None` for any other path.
+pub async fn respond(request: &IncomingRequest, history: &RwLock<History>) -> Option<Response> {
+    let api = API.as_ref()?;
+    let (route, item, path_params) = api.find(request.path.as_str())?;
+    let server = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
+    let echo = EchoResponse::new(request, server);
+    let method = request.method.as_str().to_ascii_lowercase();
+    let operation = item.iter()
+        .find(|(m, _)| *m == method)
+        .or_else(|| item.iter().find(|(m, _)| method == "head" && *m == "get"))
+        .map(|(_, operation)| operation);
+
+    let response = match operation {
+        None => {
+            let allowed: Vec<String> = item.iter().map(|(m, _)| m.to_ascii_uppercase()).collect();
+            let mut response = error_response(StatusCode::METHOD_NOT_ALLOWED, json!({
+                "error": format!("{} is not declared for {}", request.method, route.template)
+            }));
+            if let Ok(allow) = HeaderValue::from_str(&allowed.join(", ")) {
+                response.headers_mut().insert("allow", allow);
+            }
+            response
+        },
+        Some(operation) => {
+            let errors = api.validate_request(request, &echo, item, operation, &path_params);
+            let name = operation.operation_id.clone()
+                .unwrap_or_else(|| format!("{} {}", request.method, route.template));
+            if errors.is_empty() {
+                debug!(operation = name, path = echo.path(), "Mocking OpenAPI operation");
+                api.mock_response(operation, request)
+            } else {
+                error_response(StatusCode::BAD_REQUEST, json!({
+                    "error": "The request does not match the OpenAPI specification",
+                    "operation": name,
+                    "errors": errors
+                }))
+            }
+        }
+    };
+    history.write().unwrap().record(echo, response.status());
+    Some(response)
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Reply, filters::BoxedFilter, http::HeaderValue, hyper::StatusCode, reply::Response};
+
+use crate::{api::{EchoResponse, IncomingRequest}, echo, history::HISTORY};
+
+const DEFAULT_FAILURES: u64 = 3;
+const DEFAULT_STATUS: u16 = 503;
+const DEFAULT_TTL_SECS: i64 = 24 * 60 * 60;
+const DEFAULT_MAX_KEYS: usize = 10_000;
+
+lazy_static! {
+    /// The calls made so far with every retry key.
+    static ref COUNTERS: Mutex<HashMap<String, Counter>> = Mutex::new(HashMap::new());
+
+    /// How long a key is remembered after its last call, in seconds, set with the `RETRY_TTL` environment variable.
+    static ref TTL_SECS: i64 = std::env::var("RETRY_TTL")
+        .ok()
+        .and_then(|ttl| ttl.parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECS);
+
+    /// How many keys are counted at once, set with the `RETRY_MAX_KEYS` environment variable.
+    static ref MAX_KEYS: usize = std::env::var("RETRY_MAX_KEYS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_KEYS);
+}
+
+// The counters, without those of keys that have not been called for the TTL.
+fn counters() -> MutexGuard<'static, HashMap<String, Counter>> {
+    let mut counters = COUNTERS.lock().unwrap();
+    let now = Utc::now();
+    counters.retain(|_, counter| (now - counter.last_attempt).num_seconds() < *TTL_SECS);
+    counters
+}
+
+#[derive(Clone)]
+struct Counter {
+    attempts: u64,
+    first_attempt: DateTime<Utc>,
+    last_attempt: DateTime<Utc>
+}
+
+#[derive(Serialize)]
+struct CounterSummary {
+    key: String,
+    attempts: u64,
+    first_attempt: DateTime<Utc>,
+    last_attempt: DateTime<Utc>
+}
+
+impl CounterSummary {
+    fn new(key: &str, counter: &Counter) -> Self {
+        CounterSummary {
+            key: key.to_string(),
+            attempts: counter.attempts,
+            first_attempt: counter.first_attempt,
+            last_attempt: counter.last_attempt
+        }
+    }
+}
+
+/// How a retry key behaves, from the `fail`, `code` and `retry_after` query parameters.
+#[derive(Deserialize)]
+struct RetryQuery {
+    fail: Option<u64>,
+    code: Option<u16>,
+    // Seconds to send in a `Retry-After` header along with the failures.
+    retry_after: Option<u64>
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": message })),
+        status
+    ).into_response()
+}
+
+// Counts the call and returns which attempt it is, or `None` when the key is
+// new and there is no room left to count it.
+fn attempt(key: &str) -> Option<u64> {
+    let now = Utc::now();
+    let mut counters = counters();
+    if !counters.contains_key(key) && counters.len() >= *MAX_KEYS {
+        return None;
+    }
+    let counter = counters.entry(key.to_string()).or_insert(Counter {
+        attempts: 0,
+        first_attempt: now,
+        last_attempt: now
+    });
+    counter.attempts += 1;
+    counter.last_attempt = now;
+    Some(counter.attempts)
+}
+
+/// Fails the first `fail` calls made with the key, then echoes the request.
+async fn retry(key: String, request: IncomingRequest) -> Result<impl Reply, Infallible> {
+    let query: RetryQuery = match serde_urlencoded::from_str(&request.query) {
+        Ok(query) => query,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, format!("Invalid query: {}", e)))
+    };
+    let failures = query.fail.unwrap_or(DEFAULT_FAILURES);
+    let code = query.code.unwrap_or(DEFAULT_STATUS);
+    let status = match StatusCode::from_u16(code) {
+        Ok(status) if status.is_client_error() || status.is_server_error() => status,
+        _ => return Ok(error(StatusCode::BAD_REQUEST, format!("Invalid failure status {}", code)))
+    };
+
+    let Some(attempt) = attempt(&key) else {
+        return Ok(error(StatusCode::INSUFFICIENT_STORAGE, format!("All {} retry keys are in use", *MAX_KEYS)));
+    };
+    let mut response = if attempt <= failures {
+        let server = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
+        HISTORY.write().unwrap().record(EchoResponse::new(&request, server), status);
+        let mut response = warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "error": format!("Failing attempt {} of {} for key {}", attempt, failures, key),
+                "key": key,
+                "attempt": attempt,
+                "fail": failures
+            })),
+            status
+        ).into_response();
+        if let Some(seconds) = query.retry_after {
+            response.headers_mut().insert("retry-after", HeaderValue::from(seconds));
+        }
+        response
+    } else {
+        echo::controlled(request, StatusCode::OK, &HISTORY).await
+    };
+    response.headers_mut().insert("x-retry-attempt", HeaderValue::from(attempt));
+    Ok(response)
+}
+
+fn counter_not_found(key: &str) -> Response {
+    error(StatusCode::NOT_FOUND, format!("No calls made with retry key {}", key))
+}
+
+async fn list_counters() -> Result<impl Reply, Infallible> {
+    let counters = counters();
+    let mut summaries: Vec<CounterSummary> = counters.iter()
+        .map(|(key, counter)| CounterSummary::new(key, counter))
+        .collect();
+    summaries.sort_by_key(|summary| summary.first_attempt);
+    Ok(warp::reply::json(&summaries))
+}
+
+async fn get_counter(key: String) -> Result<impl Reply, Infallible> {
+    let reply = match counters().get(&key) {
+        Some(counter) => warp::reply::json(&CounterSummary::new(&key, counter)).into_response(),
+        None => counter_not_found(&key)
+    };
+    Ok(reply)
+}
+
+async fn reset_all() -> Result<impl Reply, Infallible> {
+    COUNTERS.lock().unwrap().clear();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reset_counter(key: String) -> Result<impl Reply, Infallible> {
+    let reply = match counters().remove(&key) {
+        Some(_) => StatusCode::NO_CONTENT.into_response(),
+        None => counter_not_found(&key)
+    };
+    Ok(reply)
+}
+
+pub fn retry_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(echo::incoming_request())
+        .and_then(retry)
+        .boxed()
+}
+
+pub fn counters_handler() -> BoxedFilter<(impl Reply,)> {
+    let list = warp::path::end()
+        .and(warp::get())
+        .and_then(list_counters);
+
+    let reset = warp::path("reset")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(reset_all);
+
+    let get = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_counter);
+
+    let reset_one = warp::path::param::<String>()
+        .and(warp::path("reset"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(reset_counter);
+
+    list.or(reset).or(get).or(reset_one).boxed()
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Mutex, RwLock};
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+use warp::{Filter, Reply, filters::BoxedFilter, hyper::StatusCode, reply::Response};
+
+use crate::{api::{EchoResponse, IncomingRequest}, config, history::History, matcher::RequestMatcher, mock::MockResponse};
+
+lazy_static! {
+    /// Scenarios loaded from the file named by the `SCENARIOS` environment variable.
+    static ref SCENARIOS: Vec<Scenario> = config::load_from_env::<ScenarioConfig>("SCENARIOS")
+        .map(|config| config.scenarios)
+        .unwrap_or_default();
+
+    /// The current state of every scenario, by name.
+    static ref STATES: Mutex<HashMap<String, String>> = Mutex::new(
+        SCENARIOS.iter()
+            .map(|scenario| (scenario.name.clone(), scenario.initial_state().to_string()))
+            .collect()
+    );
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScenarioConfig {
+    #[serde(default)]
+    scenarios: Vec<Scenario>
+}
+
+/// A state machine. Each state answers requests with its own responses, and
+/// moves to another state when a request matches one of its transitions.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    name: String,
+    // Defaults to the first state.
+    initial_state: Option<String>,
+    states: IndexMap<String, State>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct State {
+    #[serde(default)]
+    responses: Vec<Rule>,
+    #[serde(default)]
+    transitions: Vec<Transition>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(default)]
+    request: RequestMatcher,
+    response: MockResponse
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Transition {
+    #[serde(default)]
+    request: RequestMatcher,
+    to: String,
+    // Without a response, the request is answered by the state it leaves.
+    response: Option<MockResponse>
+}
+
+impl Scenario {
+    fn initial_state(&self) -> &str {
+        self.initial_state.as_deref()
+            .or_else(|| self.states.keys().next().map(String::as_str))
+            .unwrap_or_default()
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if !self.states.contains_key(self.initial_state()) {
+            return Err(format!("initial state {:?} is not a state", self.initial_state()));
+        }
+        for (name, state) in &self.states {
+            for transition in &state.transitions {
+                if !self.states.contains_key(&transition.to) {
+                    return Err(format!("state {} moves to unknown state {:?}", name, transition.to));
+                }
+            }
+            let responses = state.responses.iter().map(|rule| &rule.response)
+                .chain(state.transitions.iter().filter_map(|t| t.response.as_ref()));
+            for response in responses {
+                response.check().map_err(|error| format!("state {}: {}", name, error))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct ScenarioSummary {
+    name: String,
+    state: String,
+    initial_state: String,
+    states: Vec<String>
+}
+
+impl ScenarioSummary {
+    fn new(scenario: &Scenario, states: &HashMap<String, String>) -> Self {
+        ScenarioSummary {
+            name: scenario.name.clone(),
+            state: states.get(&scenario.name).cloned().unwrap_or_default(),
+            initial_state: scenario.initial_state().to_string(),
+            states: scenario.states.keys().cloned().collect()
+        }
+    }
+}
+
+/// Loads the scenarios at startup so a broken file is reported straight away.
+pub fn init() {
+    lazy_static::initialize(&SCENARIOS);
+    for scenario in SCENARIOS.iter() {
+        if let Err(error) = scenario.check() {
+            panic!("SCENARIOS: scenario {}: {}", scenario.name, error);
+        }
+    }
+    if !SCENARIOS.is_empty() {
+        info!(scenarios = SCENARIOS.len(), "Loaded scenarios");
+    }
+}
+
+/// Answers the request from the current state of the first scenario that
+/// handles it, firing the transition it matches. Returns `None` when no
+/// scenario answers the request.
+pub async fn respond(request: &IncomingRequest, history: &RwLock<History>) -> Option<Response> {
+    if SCENARIOS.is_empty() {
+        return None;
+    }
+    let server = whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
+    let echo = EchoResponse::new(request, server);
+    let response = {
+        let mut states = STATES.lock().unwrap();
+        SCENARIOS.iter().find_map(|scenario| {
+            let current = states.get(&scenario.name)?.clone();
+            let state = scenario.states.get(&current)?;
+            let rule = state.responses.iter()
+                .find(|rule| rule.request.matches(&echo))
+                .map(|rule| &rule.response);
+            match state.transitions.iter().find(|t| t.request.matches(&echo)) {
+                Some(transition) => {
+                    info!(scenario = scenario.name, from = current, to = transition.to, "Scenario state changed");
+                    states.insert(scenario.name.clone(), transition.to.clone());
+                    transition.response.as_ref().or(rule).cloned()
+                },
+                None => rule.cloned()
+            }
+        })
+    }?;
+    let response = response.reply().await;
+    history.write().unwrap().record(echo, response.status());
+    Some(response)
+}
+
+fn scenario_not_found(name: &str) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "error": format!("No scenario named {}", name)
+        })),
+        StatusCode::NOT_FOUND
+    ).into_response()
+}
+
+async fn list_scenarios() -> Result<impl Reply, Infallible> {
+    let states = STATES.lock().unwrap();
+    let summaries: Vec<ScenarioSummary> = SCENARIOS.iter()
+        .map(|scenario| ScenarioSummary::new(scenario, &states))
+        .collect();
+    Ok(warp::reply::json(&summaries))
+}
+
+async fn get_scenario(name: String) -> Result<impl Reply, Infallible> {
+    let reply = match SCENARIOS.iter().find(|s| s.name == name) {
+        Some(scenario) => warp::reply::json(&ScenarioSummary::new(scenario, &STATES.lock().unwrap())).into_response(),
+        None => scenario_not_found(&name)
+    };
+    Ok(reply)
+}
+
+async fn reset_all() -> Result<impl Reply, Infallible> {
+    let mut states = STATES.lock().unwrap();
+    for scenario in SCENARIOS.iter() {
+        states.insert(scenario.name.clone(), scenario.initial_state().to_string());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reset_scenario(name: String) -> Result<impl Reply, Infallible> {
+    let reply = match SCENARIOS.iter().find(|s| s.name == name) {
+        Some(scenario) => {
+            STATES.lock().unwrap().insert(name, scenario.initial_state().to_string());
+            StatusCode::NO_CONTENT.into_response()
+        },
+        None => scenario_not_found(&name)
+    };
+    Ok(reply)
+}
+
+#[derive(Deserialize)]
+struct SetState {
+    state: String
+}
+
+async fn set_state(name: String, body: SetState) -> Result<impl Reply, Infallible> {
+    let Some(scenario) = SCENARIOS.iter().find(|s| s.name == name) else {
+        return Ok(scenario_not_found(&name));
+    };
+    if !scenario.states.contains_key(&body.state) {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({
+                "error": format!("Scenario {} has no state {}", name, body.state)
+            })),
+            StatusCode::BAD_REQUEST
+        ).into_response());
+    }
+    let mut states = STATES.lock().unwrap();
+    states.insert(name, body.state);
+    Ok(warp::reply::json(&ScenarioSummary::new(scenario, &states)).into_response())
+}
+
+pub fn scenarios_handler() -> BoxedFilter<(impl Reply,)> {
+    let list = warp::path::end()
+        .and(warp::get())
+        .and_then(list_scenarios);
+
+    let reset = warp::path("reset")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(reset_all);
+
+    let get = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(get_scenario);
+
+    let reset_one = warp::path::param::<String>()
+        .and(warp::path("reset"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and_then(reset_scenario);
+
+    let set = warp::path::param::<String>()
+        .and(warp::path("state"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::json::<SetState>())
+        .and_then(set_state);
+
+    list.or(reset).or(get).or(reset_one).or(set).boxed()
+}
@@ -0,0 +1,291 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, StreamBody};
+use hyper::{body::{Frame, Incoming}, service::service_fn, Request};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tower_service::Service;
+use tracing::*;
+use warp::{reply::Response, Filter, Rejection, Reply};
+
+use crate::{
+    fingerprint::{self, Http2Fingerprint, Recorder, Recording, TlsFingerprint},
+    http2::{self, H2cUpgrade, StreamTagger},
+    tls::TlsInfo
+};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type BodyStream = BoxStream<'static, Result<Bytes, BoxError>>;
+
+/// Details of the connection a request arrived on. Inserted into the request
+/// extensions so filters can pick it up with `warp::ext::optional`.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub remote_addr: SocketAddr,
+    // Only set for connections to the TLS listener.
+    pub tls: Option<TlsInfo>,
+    // Only set when fingerprinting, once the first request has arrived.
+    pub http2_fingerprint: Option<Http2Fingerprint>
+}
+
+/// The HTTP/2 stream a request arrived on, inserted into the request extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamId(pub u32);
+
+// A body stream handed from a warp reply to the server, see `streaming`.
+#[derive(Clone)]
+struct StreamingBody(Arc<Mutex<Option<BodyStream>>>);
+
+/// Creates a response that streams its body. warp only builds streaming
+/// bodies internally, so the stream travels in the response extensions and
+/// the server swaps it in once the filters are done with the response.
+pub fn streaming<S, E>(stream: S) -> Response
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<BoxError> + 'static
+{
+    let stream = stream.map_err(Into::into).boxed();
+    let mut response = Response::default();
+    response.extensions_mut().insert(StreamingBody(Arc::new(Mutex::new(Some(stream)))));
+    response
+}
+
+/// Replaces the body of the response, streamed or not, with the stream the
+/// given function makes of it.
+pub fn map_body<F>(response: Response, f: F) -> Response
+where
+    F: FnOnce(BodyStream) -> BodyStream
+{
+    let (mut parts, body) = response.into_parts();
+    let stream = parts.extensions.remove::<StreamingBody>()
+        .and_then(|streaming| streaming.0.lock().unwrap().take())
+        .unwrap_or_else(|| body.into_data_stream().map_err(Into::into).boxed());
+    parts.extensions.insert(StreamingBody(Arc::new(Mutex::new(Some(f(stream))))));
+    Response::from_parts(parts, Default::default())
+}
+
+/// The length of the response body, when it is known up front.
+pub fn body_length(response: &Response) -> Option<u64> {
+    if response.extensions().get::<StreamingBody>().is_some() {
+        return None;
+    }
+    hyper::body::Body::size_hint(response.body()).exact()
+}
+
+type ServerResponse = hyper::Response<UnsyncBoxBody<Bytes, BoxError>>;
+
+fn into_body(response: Response) -> ServerResponse {
+    let (mut parts, body) = response.into_parts();
+    let stream = parts.extensions.remove::<StreamingBody>()
+        .and_then(|streaming| streaming.0.lock().unwrap().take());
+    let body = match stream {
+        Some(stream) => StreamBody::new(stream.map_ok(Frame::data)).boxed_unsync(),
+        None => body.map_err(Into::into).boxed_unsync()
+    };
+    hyper::Response::from_parts(parts, body)
+}
+
+// A TLS handshake that takes longer than this is given up on.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+async fn accept(listener: &TcpListener) -> (tokio::net::TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(error) => {
+                // Most likely out of file descriptors, so back off a bit before retrying.
+                error!(?error, "failed to accept connection");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// Handles the requests of a connection, tagging them with what is known
+// about it before passing them on to warp.
+#[derive(Clone)]
+struct Handler<S> {
+    service: S,
+    connection: Connection,
+    // What an HTTP/2 client sent first, when fingerprinting.
+    http2: Option<Recording>,
+    http2_fingerprint: Arc<OnceLock<Http2Fingerprint>>
+}
+
+impl<S> Handler<S>
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + Sync + 'static,
+    S::Future: Send
+{
+    fn new(service: S, connection: Connection, http2: Option<Recording>) -> Self {
+        Handler { service, connection, http2, http2_fingerprint: Arc::new(OnceLock::new()) }
+    }
+
+    async fn handle(self, request: Request<Incoming>) -> Result<ServerResponse, Infallible> {
+        let is_upgrade = request.version() == hyper::Version::HTTP_11
+            && self.connection.tls.is_none()
+            && http2::is_h2c_upgrade(request.headers());
+        // Upgrades that can't be replayed over HTTP/2 are declined, and the
+        // request is answered over HTTP/1.1 instead.
+        let frames = if is_upgrade && hyper::body::Body::is_end_stream(request.body()) {
+            http2::upgrade_request_frames(request.method(), request.uri(), request.headers())
+        } else {
+            None
+        };
+        match frames {
+            Some(frames) => Ok(self.upgrade(request, frames)),
+            None => self.call(request).await
+        }
+    }
+
+    // Tags the request with what is known about its connection.
+    fn tag(&self, request: &mut Request<Incoming>) {
+        let version = request.version();
+        let mut connection = self.connection.clone();
+        if version == hyper::Version::HTTP_2 {
+            if let Some(recording) = self.http2.as_ref().filter(|_| self.http2_fingerprint.get().is_none()) {
+                if let Some(fingerprint) = recording.inspect(Http2Fingerprint::new).flatten() {
+                    recording.take();
+                    let _ = self.http2_fingerprint.set(fingerprint);
+                }
+            }
+            connection.http2_fingerprint = self.http2_fingerprint.get().cloned();
+            // Added last to the header block by the `StreamTagger`, after anything the client sent by that name.
+            let stream_id = request.headers().get_all(http2::STREAM_ID_HEADER).iter().next_back()
+                .and_then(|v| v.to_str().ok()?.parse().ok());
+            request.headers_mut().remove(http2::STREAM_ID_HEADER);
+            if let Some(id) = stream_id {
+                request.extensions_mut().insert(StreamId(id));
+            }
+        }
+        request.extensions_mut().insert(connection);
+        request.extensions_mut().insert(version);
+    }
+
+    async fn call(self, request: Request<Incoming>) -> Result<ServerResponse, Infallible> {
+        // The warp service is always ready, so there is no need to poll it first.
+        let mut service = self.service.clone();
+        let response = service.call(request).await.unwrap_or_else(|e| match e {});
+        Ok(into_body(response))
+    }
+
+    // Switches the connection to HTTP/2 and answers the request that asked
+    // for it on stream 1.
+    fn upgrade(self, mut request: Request<Incoming>, frames: Bytes) -> ServerResponse {
+        let on_upgrade = hyper::upgrade::on(&mut request);
+        let remote_addr = self.connection.remote_addr;
+        let service = self.service.clone();
+        let connection = self.connection.clone();
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(error) => {
+                    debug!(?error, %remote_addr, "h2c upgrade failed");
+                    return;
+                }
+            };
+            let io = StreamTagger::new(H2cUpgrade::new(TokioIo::new(upgraded), frames));
+            let handler = Handler::new(service, connection, None);
+            let service = service_fn(move |mut request| {
+                handler.tag(&mut request);
+                handler.clone().call(request)
+            });
+            if let Err(error) = http2::builder().http2_only().serve_connection(TokioIo::new(io), service).await {
+                debug!(?error, %remote_addr, "connection error");
+            }
+        });
+        hyper::Response::builder()
+            .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
+            .header("connection", "Upgrade")
+            .header("upgrade", "h2c")
+            .body(UnsyncBoxBody::default())
+            .expect("a valid switching protocols response")
+    }
+}
+
+// Serves the connection, fingerprinting its HTTP/2 client from the recording
+// of what it sent, if there is one.
+async fn serve_connection<S, I>(service: S, io: I, connection: Connection, http2: Option<Recording>)
+where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + Sync + 'static,
+    S::Future: Send,
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let remote_addr = connection.remote_addr;
+    let handler = Handler::new(service, connection, http2);
+    let service = service_fn(move |mut request| {
+        handler.tag(&mut request);
+        handler.clone().handle(request)
+    });
+    if let Err(error) = http2::builder()
+        .serve_connection_with_upgrades(TokioIo::new(StreamTagger::new(io)), service)
+        .await
+    {
+        debug!(?error, %remote_addr, "connection error");
+    }
+}
+
+/// Binds to the given address and serves the filter on it, much like
+/// `warp::serve(filter).run(addr)`, except that every request carries its
+/// `Connection` and HTTP `Version` as extensions. Besides HTTP/1, the listener speaks cleartext HTTP/2, either
+/// with prior knowledge or after an h2c upgrade.
+pub async fn run<F, R>(filter: F, addr: SocketAddr)
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply
+{
+    let listener = TcpListener::bind(addr).await.expect("failed to bind to address");
+    let service = warp::service(filter);
+
+    loop {
+        let (stream, remote_addr) = accept(&listener).await;
+        let connection = Connection { remote_addr, tls: None, http2_fingerprint: None };
+        tokio::spawn(serve_connection(service.clone(), stream, connection, None));
+    }
+}
+
+/// Like `run`, but over TLS. The `Connection` of every request carries what
+/// was negotiated during the handshake, and when fingerprinting, the
+/// fingerprints of the client.
+pub async fn run_tls<F, R>(filter: F, addr: SocketAddr, acceptor: TlsAcceptor)
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply
+{
+    let listener = TcpListener::bind(addr).await.expect("failed to bind to TLS address");
+    let service = warp::service(filter);
+
+    loop {
+        let (stream, remote_addr) = accept(&listener).await;
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = Recorder::new(stream, *fingerprint::ENABLED);
+            let client_hello = stream.recording();
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(error)) => {
+                    debug!(?error, %remote_addr, "TLS handshake failed");
+                    return;
+                },
+                Err(_) => {
+                    debug!(%remote_addr, "TLS handshake timed out");
+                    return;
+                }
+            };
+            let mut tls = TlsInfo::new(stream.get_ref().1);
+            tls.fingerprint = TlsFingerprint::new(&client_hello.take());
+            let is_http2 = tls.alpn_protocol.as_deref() == Some("h2");
+            let connection = Connection { remote_addr, tls: Some(tls), http2_fingerprint: None };
+            let stream = Recorder::new(stream, *fingerprint::ENABLED && is_http2);
+            let http2 = Some(stream.recording()).filter(|_| *fingerprint::ENABLED && is_http2);
+            serve_connection(service, stream, connection, http2).await;
+        });
+    }
+}
@@ -0,0 +1,196 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderErrorReason
+};
+use rand::{distr::Alphanumeric, seq::IndexedRandom, Rng};
+use serde_json::{json, Map, Value};
+use tracing::*;
+use uuid::Uuid;
+use warp::{http::HeaderValue, hyper::{HeaderMap, StatusCode}, reply::Response};
+
+use crate::api::EchoResponse;
+
+// Templates are files named `{name}.hbs`, or `{name}.{extension}.hbs` to set the content type.
+const TEMPLATE_EXTENSION: &str = ".hbs";
+
+lazy_static! {
+    /// The directory named by the `TEMPLATE_DIR` environment variable.
+    static ref TEMPLATE_DIR: Option<PathBuf> = std::env::var("TEMPLATE_DIR")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from);
+
+    // Only HTML templates get their values escaped, JSON and the like are written as is.
+    static ref HANDLEBARS: Handlebars<'static> = registry(false);
+    static ref HTML_HANDLEBARS: Handlebars<'static> = registry(true);
+}
+
+// Written out rather than with `handlebars_helper!`, as a bad strftime format
+// has to fail the render instead of panicking.
+fn now(helper: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let format = match helper.hash_get("format").map(|format| format.value()) {
+        None => "rfc3339",
+        Some(Value::String(format)) => format.as_str(),
+        Some(other) => return Err(RenderErrorReason::Other(format!("now: format must be a string, not {}", other)).into())
+    };
+    let now = Utc::now();
+    let text = match format {
+        "rfc3339" => now.to_rfc3339(),
+        "unix" => now.timestamp().to_string(),
+        "millis" => now.timestamp_millis().to_string(),
+        format => {
+            let mut text = String::new();
+            write!(text, "{}", now.format(format))
+                .map_err(|_| RenderErrorReason::Other(format!("now: invalid format {}", format)))?;
+            text
+        }
+    };
+    out.write(&text)?;
+    Ok(())
+}
+
+handlebars_helper!(random_int: |min: i64, max: i64| rand::rng().random_range(min.min(max)..=max.max(min)));
+
+handlebars_helper!(random_float: |{min: f64 = 0.0, max: f64 = 1.0}| {
+    if min < max { rand::rng().random_range(min..max) } else { min }
+});
+
+handlebars_helper!(random_string: |{length: u64 = 16}| {
+    rand::rng().sample_iter(Alphanumeric).take(length as usize).map(char::from).collect::<String>()
+});
+
+handlebars_helper!(pick: |*values| values.choose(&mut rand::rng()).map(|v| (*v).clone()).unwrap_or(Value::Null));
+
+handlebars_helper!(to_json: |value: Json| value.to_string());
+
+fn uuid(_: &Helper, _: &Handlebars, _: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    out.write(&Uuid::new_v4().to_string())?;
+    Ok(())
+}
+
+fn registry(html: bool) -> Handlebars<'static> {
+    let mut handlebars = Handlebars::new();
+    if !html {
+        handlebars.register_escape_fn(handlebars::no_escape);
+    }
+    handlebars.register_helper("uuid", Box::new(uuid));
+    handlebars.register_helper("now", Box::new(now));
+    handlebars.register_helper("random_int", Box::new(random_int));
+    handlebars.register_helper("random_float", Box::new(random_float));
+    handlebars.register_helper("random_string", Box::new(random_string));
+    handlebars.register_helper("pick", Box::new(pick));
+    handlebars.register_helper("to_json", Box::new(to_json));
+    handlebars
+}
+
+/// The template asked for with the `template` query parameter or the
+/// `X-Echo-Template` header. The query parameter takes precedence.
+pub fn requested(query: &str, headers: &HeaderMap) -> Option<String> {
+    let params: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+    params.into_iter()
+        .rev()
+        .find(|(k, _)| k == "template")
+        .map(|(_, v)| v)
+        .or_else(|| headers.get("x-echo-template").and_then(|v| v.to_str().ok()).map(str::to_string))
+        .filter(|name| !name.is_empty())
+}
+
+// Finds the template file along with the extension naming its content type.
+fn find(dir: &Path, name: &str) -> Option<(PathBuf, Option<String>)> {
+    std::fs::read_dir(dir).ok()?
+        .filter_map(Result::ok)
+        .find_map(|entry| {
+            let file_name = entry.file_name().into_string().ok()?;
+            let stem = file_name.strip_suffix(TEMPLATE_EXTENSION)?;
+            match stem.split_once('.') {
+                None if stem == name => Some((entry.path(), None)),
+                Some((stem, extension)) if stem == name => Some((entry.path(), Some(extension.to_string()))),
+                _ => None
+            }
+        })
+}
+
+fn content_type(extension: Option<&str>) -> &'static str {
+    match extension.map(str::to_ascii_lowercase).as_deref() {
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("yaml") | Some("yml") => "application/yaml",
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        _ => "text/plain; charset=utf-8"
+    }
+}
+
+/// The data templates are rendered with. Besides the echo itself, available
+/// as `request`, it offers the path `segments`, the first value of each query
+/// parameter, headers by lowercase name, and the parsed `json` body.
+fn context(echo: &EchoResponse) -> Value {
+    let request = serde_json::to_value(echo).unwrap_or(Value::Null);
+    let mut context = request.as_object().cloned().unwrap_or_default();
+    let segments: Vec<&str> = echo.path().split('/').filter(|s| !s.is_empty()).collect();
+    let query: Map<String, Value> = echo.query().iter()
+        .filter_map(|(name, values)| Some((name.clone(), json!(values.first()?))))
+        .collect();
+    let mut headers = Map::new();
+    for (name, value) in echo.headers() {
+        let name = name.to_ascii_lowercase();
+        match headers.get_mut(&name) {
+            Some(Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            },
+            _ => {
+                headers.insert(name, json!(value));
+            }
+        }
+    }
+    context.insert("segments".to_string(), json!(segments));
+    context.insert("query_all".to_string(), json!(echo.query()));
+    context.insert("query".to_string(), Value::Object(query));
+    context.insert("headers".to_string(), Value::Object(headers));
+    context.insert("request".to_string(), request);
+    Value::Object(context)
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    let mut response = Response::new(json!({ "error": message }).to_string().into());
+    *response.status_mut() = status;
+    response.headers_mut().insert("content-type", HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Renders the named template with the echoed request, answering with the
+/// given status. A template that is missing or fails to render is reported
+/// with an error status instead.
+pub fn render(name: &str, echo: &EchoResponse, status: StatusCode) -> Response {
+    let Some(dir) = TEMPLATE_DIR.as_ref() else {
+        return error(StatusCode::BAD_REQUEST, "Response templates are not enabled, set TEMPLATE_DIR".to_string());
+    };
+    let valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+    let Some((path, extension)) = find(dir, name).filter(|_| valid_name) else {
+        return error(StatusCode::BAD_REQUEST, format!("No template named {}", name));
+    };
+    let template = match std::fs::read_to_string(&path) {
+        Ok(template) => template,
+        Err(e) => {
+            error!(path = %path.display(), error = %e, "failed to read template");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read template {}", name));
+        }
+    };
+    let content_type = content_type(extension.as_deref());
+    let handlebars = if content_type.starts_with("text/html") { &*HTML_HANDLEBARS } else { &*HANDLEBARS };
+    let body = match handlebars.render_template(&template, &context(echo)) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(template = name, error = %e, "failed to render template");
+            return error(StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to render template {}: {}", name, e));
+        }
+    };
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response.headers_mut().insert("content-type", HeaderValue::from_static(content_type));
+    response
+}
@@ -0,0 +1,236 @@
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig, ServerConnection,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        server::WebPkiClientVerifier
+    }
+};
+use tracing::*;
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName};
+
+use crate::fingerprint::TlsFingerprint;
+
+/// What was negotiated on a TLS connection.
+#[derive(Clone, Debug, Serialize)]
+pub struct TlsInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cipher_suite: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpn_protocol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate: Option<ClientCertificate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<TlsFingerprint>
+}
+
+/// The certificate a client authenticated with.
+#[derive(Clone, Debug, Serialize)]
+pub struct ClientCertificate {
+    pub subject: String,
+    pub issuer: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subject_alt_names: Vec<String>,
+    pub serial: String,
+    pub not_before: Option<DateTime<Utc>>,
+    pub not_after: Option<DateTime<Utc>>,
+    pub sha256_fingerprint: String
+}
+
+impl ClientCertificate {
+    fn new(der: &CertificateDer) -> Option<Self> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(der)
+            .inspect_err(|error| warn!(%error, "failed to parse client certificate"))
+            .ok()?;
+        let validity = certificate.validity();
+        Some(ClientCertificate {
+            subject: certificate.subject().to_string(),
+            issuer: certificate.issuer().to_string(),
+            subject_alt_names: subject_alt_names(&certificate),
+            serial: certificate.raw_serial_as_string(),
+            not_before: DateTime::from_timestamp(validity.not_before.timestamp(), 0),
+            not_after: DateTime::from_timestamp(validity.not_after.timestamp(), 0),
+            sha256_fingerprint: format!("{:x}", Sha256::digest(der))
+        })
+    }
+
+    /// The certificate as rows of the HTML page.
+    pub fn details(&self) -> Vec<(String, String)> {
+        let validity = |time: Option<DateTime<Utc>>| time.map(|t| t.to_rfc3339()).unwrap_or_else(|| "unknown".to_string());
+        let mut details = vec![
+            ("Client Subject".to_string(), self.subject.clone()),
+            ("Client Issuer".to_string(), self.issuer.clone())
+        ];
+        if !self.subject_alt_names.is_empty() {
+            details.push(("Client SANs".to_string(), self.subject_alt_names.join(", ")));
+        }
+        details.push(("Client Serial".to_string(), self.serial.clone()));
+        details.push(("Client Validity".to_string(), format!("{} to {}", validity(self.not_before), validity(self.not_after))));
+        details.push(("Client SHA-256".to_string(), self.sha256_fingerprint.clone()));
+        details
+    }
+}
+
+// The alternative names in the style of openssl, such as `DNS:example.com`.
+fn subject_alt_names(certificate: &X509Certificate) -> Vec<String> {
+    let Ok(Some(extension)) = certificate.subject_alternative_name() else {
+        return Vec::new();
+    };
+    extension.value.general_names.iter()
+        .map(|name| match name {
+            GeneralName::DNSName(dns) => format!("DNS:{}", dns),
+            GeneralName::RFC822Name(email) => format!("email:{}", email),
+            GeneralName::URI(uri) => format!("URI:{}", uri),
+            GeneralName::IPAddress(bytes) => match <[u8; 4]>::try_from(*bytes) {
+                Ok(v4) => format!("IP:{}", std::net::Ipv4Addr::from(v4)),
+                Err(_) => match <[u8; 16]>::try_from(*bytes) {
+                    Ok(v6) => format!("IP:{}", std::net::Ipv6Addr::from(v6)),
+                    Err(_) => format!("IP:{:x?}", bytes)
+                }
+            },
+            other => other.to_string()
+        })
+        .collect()
+}
+
+impl TlsInfo {
+    pub fn new(connection: &ServerConnection) -> Self {
+        let version = connection.protocol_version().map(|version| {
+            let name = version.as_str().map(str::to_string).unwrap_or_else(|| format!("{:?}", version));
+            // rustls names them TLSv1_3 and the like, where everyone else writes TLSv1.3.
+            name.replace('_', ".")
+        });
+        let cipher_suite = connection.negotiated_cipher_suite().map(|suite| {
+            let suite = suite.suite();
+            suite.as_str().map(str::to_string).unwrap_or_else(|| format!("{:?}", suite))
+        });
+        TlsInfo {
+            version,
+            cipher_suite,
+            server_name: connection.server_name().map(str::to_string),
+            alpn_protocol: connection.alpn_protocol().map(|p| String::from_utf8_lossy(p).to_string()),
+            client_certificate: connection.peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .and_then(ClientCertificate::new),
+            fingerprint: None
+        }
+    }
+
+    /// A one line summary, such as `TLSv1.3 / TLS13_AES_256_GCM_SHA384`.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} / {}",
+            self.version.as_deref().unwrap_or("unknown"),
+            self.cipher_suite.as_deref().unwrap_or("unknown")
+        );
+        if let Some(server_name) = &self.server_name {
+            summary.push_str(&format!(" (SNI {})", server_name));
+        }
+        if let Some(alpn) = &self.alpn_protocol {
+            summary.push_str(&format!(" (ALPN {})", alpn));
+        }
+        summary
+    }
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let certificates = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid {}: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificates in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let pem = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|e| format!("invalid {}: {}", path.display(), e))?
+        .ok_or_else(|| format!("no private key in {}", path.display()))
+}
+
+// A certificate for localhost and the host name, made up at startup.
+fn self_signed() -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    if let Ok(hostname) = whoami::fallible::hostname() {
+        names.push(hostname);
+    }
+    let certified = rcgen::generate_simple_self_signed(names.clone())
+        .expect("failed to generate a self-signed certificate");
+    let certificate = certified.cert.der().clone();
+    let fingerprint = format!("{:x}", Sha256::digest(&certificate));
+    info!(?names, sha256 = fingerprint, "Generated a self-signed certificate");
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    (vec![certificate], key)
+}
+
+/// The address and acceptor of the TLS listener, which is started when the
+/// `TLS_HOST_PORT` environment variable is set. The certificate chain and key
+/// are read from the PEM files named by `TLS_CERT` and `TLS_KEY`, or else a
+/// self-signed certificate is generated. Clients are asked for a certificate
+/// issued by the CAs in `TLS_CLIENT_CA`, which `TLS_CLIENT_AUTH` makes
+/// `optional` (the default) or `required`. Broken settings are fatal.
+pub fn listener() -> Option<(SocketAddr, TlsAcceptor)> {
+    let addr = std::env::var("TLS_HOST_PORT").ok().filter(|a| !a.is_empty())?;
+    let addr = SocketAddr::from_str(&addr)
+        .unwrap_or_else(|e| panic!("TLS_HOST_PORT: invalid address {}: {}", addr, e));
+
+    let cert = std::env::var("TLS_CERT").ok().filter(|p| !p.is_empty());
+    let key = std::env::var("TLS_KEY").ok().filter(|p| !p.is_empty());
+    let (certificates, key) = match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let certificates = read_certificates(Path::new(&cert)).unwrap_or_else(|e| panic!("TLS_CERT: {}", e));
+            let key = read_private_key(Path::new(&key)).unwrap_or_else(|e| panic!("TLS_KEY: {}", e));
+            (certificates, key)
+        },
+        (None, None) => self_signed(),
+        _ => panic!("TLS_CERT and TLS_KEY must be set together")
+    };
+
+    let provider = Arc::new(ring::default_provider());
+    let client_ca = std::env::var("TLS_CLIENT_CA").ok().filter(|p| !p.is_empty());
+    let client_auth = std::env::var("TLS_CLIENT_AUTH").ok().filter(|a| !a.is_empty());
+    let verifier = match (client_ca, client_auth.as_deref()) {
+        (Some(ca), auth) => {
+            let required = match auth {
+                None | Some("optional") => false,
+                Some("required") => true,
+                Some(other) => panic!("TLS_CLIENT_AUTH: expected optional or required, not {}", other)
+            };
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(
+                read_certificates(Path::new(&ca)).unwrap_or_else(|e| panic!("TLS_CLIENT_CA: {}", e))
+            );
+            let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+            let builder = if required { builder } else { builder.allow_unauthenticated() };
+            info!(certificates = added, required, "Verifying client certificates");
+            builder.build().unwrap_or_else(|e| panic!("TLS_CLIENT_CA: {}", e))
+        },
+        (None, Some(_)) => panic!("TLS_CLIENT_AUTH needs TLS_CLIENT_CA"),
+        (None, None) => WebPkiClientVerifier::no_client_auth()
+    };
+
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("the default TLS versions are supported")
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certificates, key)
+        .unwrap_or_else(|e| panic!("TLS_CERT: the certificate does not go with the key: {}", e));
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Some((addr, TlsAcceptor::from(Arc::new(config))))
+}
@@ -0,0 +1,153 @@
+use std::convert::Infallible;
+use std::sync::RwLock;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Reply, filters::BoxedFilter, hyper::StatusCode, reply::Response};
+
+use crate::{bins, history::{History, HISTORY}, matcher::RequestMatcher};
+
+// The number of near-misses reported when the expectation isn't met.
+const NEAR_MISSES: usize = 5;
+
+/// An expectation about the requests received. Without a count, at least one
+/// matching request is expected.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Verification {
+    #[serde(default)]
+    request: RequestMatcher,
+    count: Option<usize>,
+    at_least: Option<usize>,
+    at_most: Option<usize>,
+    // Only requests received from this moment on are considered.
+    since: Option<DateTime<Utc>>,
+    // Verifies the requests captured by a bin instead of the request history.
+    bin: Option<String>
+}
+
+impl Verification {
+    fn bounds(&self) -> (usize, Option<usize>) {
+        match (self.count, self.at_least, self.at_most) {
+            (Some(count), _, _) => (count, Some(count)),
+            (None, None, None) => (1, None),
+            (None, at_least, at_most) => (at_least.unwrap_or(0), at_most)
+        }
+    }
+
+    fn expected(&self) -> String {
+        match self.bounds() {
+            (min, Some(max)) if min == max => format!("exactly {}", min),
+            (0, Some(max)) => format!("at most {}", max),
+            (min, Some(max)) => format!("between {} and {}", min, max),
+            (min, None) => format!("at least {}", min)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NearMiss {
+    id: String,
+    timestamp: DateTime<Utc>,
+    method: String,
+    path: String,
+    mismatches: Vec<String>
+}
+
+#[derive(Serialize)]
+struct VerificationResult {
+    satisfied: bool,
+    expected: String,
+    count: usize,
+    // Requests that may have matched were dropped from the history, so the
+    // count is a lower bound.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    near_misses: Vec<NearMiss>
+}
+
+fn verify(verification: &Verification, history: &RwLock<History>) -> VerificationResult {
+    let history = history.read().unwrap();
+    let entries = history.entries()
+        .filter(|entry| verification.since.is_none_or(|since| entry.timestamp >= since));
+    let mut count = 0;
+    let mut misses = Vec::new();
+    for entry in entries {
+        let mismatches = verification.request.mismatches(&entry.request);
+        if mismatches.is_empty() {
+            count += 1;
+        } else {
+            misses.push(NearMiss {
+                id: entry.id.clone(),
+                timestamp: entry.timestamp,
+                method: entry.request.method().to_string(),
+                path: entry.request.path().to_string(),
+                mismatches
+            });
+        }
+    }
+    let truncated = history.is_truncated(verification.since);
+    let (min, max) = verification.bounds();
+    // Dropped requests can only add matches, so an upper bound can't be
+    // confirmed once the history is truncated.
+    let satisfied = count >= min && max.is_none_or(|max| count <= max && !truncated);
+    // With too few matches, the requests that came closest to matching are
+    // reported, most recent first among equals.
+    let near_misses = if count >= min {
+        Vec::new()
+    } else {
+        misses.reverse();
+        misses.sort_by_key(|miss| miss.mismatches.len());
+        misses.truncate(NEAR_MISSES);
+        misses
+    };
+    VerificationResult {
+        satisfied,
+        expected: verification.expected(),
+        count,
+        truncated,
+        near_misses
+    }
+}
+
+fn bad_request(error: String) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": error })),
+        StatusCode::BAD_REQUEST
+    ).into_response()
+}
+
+async fn verify_requests(body: Bytes) -> Result<impl Reply, Infallible> {
+    let verification = match serde_json::from_slice::<Verification>(&body) {
+        Ok(verification) => verification,
+        Err(error) => return Ok(bad_request(format!("Invalid verification: {}", error)))
+    };
+    if let (Some(min), Some(max)) = (verification.at_least, verification.at_most) {
+        if min > max {
+            return Ok(bad_request(format!("at_least {} is greater than at_most {}", min, max)));
+        }
+    }
+    let result = match &verification.bin {
+        Some(id) => match bins::history(id) {
+            Some(history) => verify(&verification, &history),
+            None => return Ok(warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "error": format!("No bin with id {}", id)
+                })),
+                StatusCode::NOT_FOUND
+            ).into_response())
+        },
+        None => verify(&verification, &HISTORY)
+    };
+    Ok(warp::reply::json(&result).into_response())
+}
+
+pub fn verify_handler() -> BoxedFilter<(impl Reply,)> {
+    warp::path::end()
+        .and(warp::post())
+        .and(warp::body::bytes())
+        .and_then(verify_requests)
+        .boxed()
+}